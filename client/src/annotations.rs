@@ -0,0 +1,187 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Local notes and tags on transactions.
+//!
+//! Businesses often need to associate payments with their own records, such as invoice numbers or
+//! customer references. [TransactionAnnotations] stores free-form text and key-value tags for
+//! transactions, indexed by receipt, so they can be shown alongside the keystore's transaction
+//! history and searched.
+//!
+//! Annotations are private to the keystore which creates them. They are stored in a single local
+//! file, typically alongside the keystore, and are never published to the ledger or shared with
+//! counterparties.
+
+use crate::{persistence, EspressoKeystoreError};
+use espresso_core::{ledger::EspressoLedger, state::TransactionCommitment};
+use seahorse::ledger_state::TransactionUID;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// The annotations on a single transaction.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub note: String,
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A persistent collection of [Annotation]s, indexed by transaction.
+#[derive(Debug)]
+pub struct TransactionAnnotations {
+    path: PathBuf,
+    annotations: HashMap<TransactionCommitment, Annotation>,
+}
+
+impl TransactionAnnotations {
+    /// Load the annotations stored at `path`.
+    ///
+    /// If `path` does not exist, there are no annotations, and the file will be created the first
+    /// time a transaction is annotated.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, EspressoKeystoreError> {
+        let path = path.into();
+        let annotations = persistence::load(&path, "transaction annotations")?;
+        Ok(Self { path, annotations })
+    }
+
+    pub fn get(&self, receipt: &TransactionUID<EspressoLedger>) -> Option<&Annotation> {
+        self.annotations.get(&Self::key(receipt))
+    }
+
+    /// Set the free-form note on a transaction, replacing any existing note.
+    pub fn set_note(
+        &mut self,
+        receipt: &TransactionUID<EspressoLedger>,
+        note: impl Into<String>,
+    ) -> Result<(), EspressoKeystoreError> {
+        self.annotations.entry(Self::key(receipt)).or_default().note = note.into();
+        self.save()
+    }
+
+    /// Set a tag on a transaction, replacing any existing value for `key`.
+    pub fn set_tag(
+        &mut self,
+        receipt: &TransactionUID<EspressoLedger>,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), EspressoKeystoreError> {
+        self.annotations
+            .entry(Self::key(receipt))
+            .or_default()
+            .tags
+            .insert(key.into(), value.into());
+        self.save()
+    }
+
+    /// Remove a tag from a transaction, returning its value if it was set.
+    pub fn remove_tag(
+        &mut self,
+        receipt: &TransactionUID<EspressoLedger>,
+        key: &str,
+    ) -> Result<Option<String>, EspressoKeystoreError> {
+        let value = self
+            .annotations
+            .get_mut(&Self::key(receipt))
+            .and_then(|annotation| annotation.tags.remove(key));
+        if value.is_some() {
+            self.save()?;
+        }
+        Ok(value)
+    }
+
+    /// Remove all annotations from a transaction, returning them if there were any.
+    pub fn clear(
+        &mut self,
+        receipt: &TransactionUID<EspressoLedger>,
+    ) -> Result<Option<Annotation>, EspressoKeystoreError> {
+        let annotation = self.annotations.remove(&Self::key(receipt));
+        if annotation.is_some() {
+            self.save()?;
+        }
+        Ok(annotation)
+    }
+
+    /// Find transactions whose tag `key` has the value `value`.
+    pub fn with_tag<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+    ) -> impl Iterator<Item = (TransactionUID<EspressoLedger>, &'a Annotation)> + 'a {
+        self.find(move |annotation| annotation.tags.get(key).map(String::as_str) == Some(value))
+    }
+
+    /// Find transactions whose note contains `text`, ignoring case.
+    pub fn search<'a>(
+        &'a self,
+        text: &str,
+    ) -> impl Iterator<Item = (TransactionUID<EspressoLedger>, &'a Annotation)> + 'a {
+        let text = text.to_lowercase();
+        self.find(move |annotation| annotation.note.to_lowercase().contains(&text))
+    }
+
+    fn find<'a>(
+        &'a self,
+        pred: impl 'a + Fn(&Annotation) -> bool,
+    ) -> impl Iterator<Item = (TransactionUID<EspressoLedger>, &'a Annotation)> + 'a {
+        self.annotations
+            .iter()
+            .filter(move |(_, annotation)| pred(annotation))
+            .map(|(hash, annotation)| (TransactionUID(hash.0), annotation))
+    }
+
+    fn key(receipt: &TransactionUID<EspressoLedger>) -> TransactionCommitment {
+        TransactionCommitment(receipt.0)
+    }
+
+    fn save(&self) -> Result<(), EspressoKeystoreError> {
+        persistence::save(&self.path, &self.annotations, "transaction annotations")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_serialize::CanonicalDeserialize;
+    use tempdir::TempDir;
+
+    fn receipt(byte: u8) -> TransactionUID<EspressoLedger> {
+        TransactionUID(
+            TransactionCommitment::deserialize(&[byte; 32][..])
+                .unwrap()
+                .0,
+        )
+    }
+
+    #[test]
+    fn test_annotations() {
+        let dir = TempDir::new("annotations").unwrap();
+        let path = dir.path().join("annotations");
+        let rent = receipt(1);
+        let mut annotations = TransactionAnnotations::load(&path).unwrap();
+        assert_eq!(annotations.get(&rent), None);
+
+        annotations.set_note(&rent, "Rent for March").unwrap();
+        annotations.set_tag(&rent, "invoice", "INV-42").unwrap();
+        annotations.set_tag(&rent, "category", "housing").unwrap();
+        assert_eq!(
+            annotations.remove_tag(&rent, "category").unwrap(),
+            Some("housing".into())
+        );
+        assert_eq!(annotations.remove_tag(&rent, "category").unwrap(), None);
+
+        // Annotations survive a restart, and can be searched.
+        let mut annotations = TransactionAnnotations::load(&path).unwrap();
+        let annotation = annotations.get(&rent).unwrap();
+        assert_eq!(annotation.note, "Rent for March");
+        assert_eq!(annotation.tags.len(), 1);
+        assert_eq!(annotations.with_tag("invoice", "INV-42").count(), 1);
+        assert_eq!(annotations.with_tag("invoice", "INV-43").count(), 0);
+        assert_eq!(annotations.get(&receipt(2)), None);
+        assert_eq!(annotations.search("rent for").count(), 1);
+        assert_eq!(annotations.search("april").count(), 0);
+
+        assert!(annotations.clear(&rent).unwrap().is_some());
+        let annotations = TransactionAnnotations::load(&path).unwrap();
+        assert_eq!(annotations.get(&rent), None);
+    }
+}
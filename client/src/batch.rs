@@ -0,0 +1,346 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Batched transfers to many receivers.
+//!
+//! A single transfer can only pay as many receivers as the largest supported transfer circuit has
+//! outputs to spare, which is usually only one or two. [transfer_many] packs a long list of payouts
+//! (for example, a payroll) into as few transfers as the supported circuit sizes allow, using the
+//! plans made by [plan_transfer]. If the payouts need more records than a single transfer can
+//! spend, the plan starts by merging records, and those merges are made first, as transfers from
+//! the paying account to itself. Each transfer generates and publishes memos for its own outputs,
+//! so every receiver is able to discover their record as usual.
+
+use crate::{
+    redact::sensitive,
+    spendable::{spendable_records, total},
+    transfer::{transfer, TransferFailure},
+    EspressoKeystore, EspressoKeystoreError,
+};
+use espresso_core::{
+    ledger::EspressoLedger,
+    transfer_plan::{plan_transfer, PlannedTransfer},
+};
+use futures::future::join_all;
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::AssetCode,
+};
+use seahorse::{
+    ledger_state::{TransactionStatus, TransactionUID},
+    KeystoreBackend, RecordAmount,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::{self, Display, Formatter};
+use tracing::{info, warn};
+
+/// A batched transfer which failed part way through.
+#[derive(Debug)]
+pub struct BatchError {
+    /// The receipts of the transfers which were submitted before the failure, in order. These
+    /// transfers may still complete.
+    pub receipts: Vec<TransactionUID<EspressoLedger>>,
+    /// Whether the failed transfer itself may have been submitted. If so, it may still complete,
+    /// but there is no receipt for it.
+    pub maybe_submitted: bool,
+    pub error: EspressoKeystoreError,
+}
+
+impl BatchError {
+    fn new(receipts: Vec<TransactionUID<EspressoLedger>>, failure: TransferFailure) -> Self {
+        Self {
+            receipts,
+            maybe_submitted: matches!(failure, TransferFailure::MaybeSubmitted(_)),
+            error: failure.into_error(),
+        }
+    }
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "batched transfer failed after {} transfers: {}",
+            self.receipts.len(),
+            self.error
+        )
+    }
+}
+
+/// Pay each of `receivers` in `asset` from `account`, using as few transfers as possible.
+///
+/// Transfers are built and submitted as quickly as the account's records allow. If a transfer
+/// cannot be built because the records it needs are tied up in transfers which are still pending,
+/// this waits for the pending transfers to finish and tries again. Merges planned for fragmented
+/// records are waited for before any payment is made, since the payments spend their outputs.
+///
+/// Each transfer, including each merge, pays `fee`. On success, the receipts of all the transfers
+/// are returned in order. If a transfer fails, the [BatchError] holds the receipts of the
+/// transfers which were already submitted, so the caller can tell which receivers were paid. A
+/// transfer whose submission fails is never retried, since it may still have been submitted.
+pub async fn transfer_many<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    account: &UserAddress,
+    asset: &AssetCode,
+    receivers: Vec<(UserPubKey, RecordAmount)>,
+    fee: RecordAmount,
+) -> Result<Vec<TransactionUID<EspressoLedger>>, BatchError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let plan = plan_payouts(keystore, account, asset, &receivers, fee)
+        .await
+        .map_err(|error| BatchError {
+            receipts: Vec::new(),
+            maybe_submitted: false,
+            error,
+        })?;
+    info!(
+        "paying {} receivers in {} transfers",
+        receivers.len(),
+        plan.len()
+    );
+
+    let mut receipts = Vec::new();
+    // Receipts of transfers we have submitted but have not yet waited for.
+    let mut pending: Vec<TransactionUID<EspressoLedger>> = Vec::new();
+    let mut remaining = receivers.as_slice();
+    for (i, note) in plan.iter().enumerate() {
+        if note.receivers == 0 {
+            let receipt = match merge_records(keystore, account, asset, note, fee).await {
+                Ok(Some(receipt)) => receipt,
+                Ok(None) => continue,
+                Err(failure) => return Err(BatchError::new(receipts, failure)),
+            };
+            receipts.push(receipt.clone());
+            // The payments spend the merged record, so wait for it.
+            let error = match keystore.await_transaction(&receipt).await {
+                Ok(TransactionStatus::Retired) => continue,
+                Ok(status) => EspressoKeystoreError::Failed {
+                    msg: format!("merge before batched transfer did not complete: {}", status),
+                },
+                Err(error) => error,
+            };
+            return Err(BatchError {
+                receipts,
+                maybe_submitted: false,
+                error,
+            });
+        }
+
+        let (chunk, rest) = remaining.split_at(note.receivers);
+        remaining = rest;
+        let receipt = match transfer(keystore, Some(account), asset, chunk, fee).await {
+            Ok(receipt) => receipt,
+            Err(failure) if failure.is_insufficient_balance() && !pending.is_empty() => {
+                // The change from a pending transfer may be needed to fund this one. Nothing was
+                // submitted, so wait for the pending transfers and then try again.
+                info!(
+                    "transfer {} failed ({}), waiting for {} pending transfers",
+                    i,
+                    sensitive(&failure),
+                    pending.len()
+                );
+                for result in join_all(
+                    pending
+                        .iter()
+                        .map(|receipt| keystore.await_transaction(receipt)),
+                )
+                .await
+                {
+                    match result {
+                        Ok(TransactionStatus::Retired) => {}
+                        Ok(status) => warn!("batched transfer did not complete: {}", status),
+                        Err(err) => warn!("batched transfer did not complete: {}", sensitive(&err)),
+                    }
+                }
+                pending.clear();
+                match transfer(keystore, Some(account), asset, chunk, fee).await {
+                    Ok(receipt) => receipt,
+                    Err(failure) => return Err(BatchError::new(receipts, failure)),
+                }
+            }
+            Err(failure) => return Err(BatchError::new(receipts, failure)),
+        };
+        pending.push(receipt.clone());
+        receipts.push(receipt);
+    }
+    Ok(receipts)
+}
+
+/// Plan the transfers which pay `receivers` from the spendable records of `account`.
+///
+/// The plan spends as few of the largest records as cover the payouts, and for the native asset,
+/// the fees of every transfer in the plan. Each transfer spends records of a single owner, so only
+/// the records of `account` are considered.
+async fn plan_payouts<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    account: &UserAddress,
+    asset: &AssetCode,
+    receivers: &[(UserPubKey, RecordAmount)],
+    fee: RecordAmount,
+) -> Result<Vec<PlannedTransfer>, EspressoKeystoreError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    if receivers.is_empty() {
+        return Ok(Vec::new());
+    }
+    let native = *asset == AssetCode::native();
+    let records = spendable_records(keystore, Some(account), asset).await;
+    let payouts = receivers.iter().fold(0u128, |total, (_, amount)| {
+        total.saturating_add(amount.as_u128())
+    });
+
+    // Spending more records may require more merges, which pay more fees, which may require more
+    // records, so grow the number of inputs until it covers the plan it produces.
+    let mut inputs = 1;
+    loop {
+        let plan = plan_transfer(native, inputs, receivers.len()).ok_or_else(|| {
+            EspressoKeystoreError::Failed {
+                msg: "no supported transfer size can pay these receivers".into(),
+            }
+        })?;
+        let needed = if native {
+            payouts.saturating_add(fee.as_u128().saturating_mul(plan.len() as u128))
+        } else {
+            payouts
+        };
+        let covering = (1..=records.len()).find(|n| total(&records[..*n]) >= needed);
+        match covering {
+            Some(n) if n <= inputs => return Ok(plan),
+            Some(n) => inputs = n,
+            None => {
+                return Err(EspressoKeystoreError::Failed {
+                    msg: format!(
+                        "insufficient balance: {} of {} needed to pay {} receivers",
+                        total(&records),
+                        needed,
+                        receivers.len()
+                    ),
+                })
+            }
+        }
+    }
+}
+
+/// Submit the planned merge `note`.
+///
+/// The largest spendable records of `account` are merged into one record owned by `account`.
+/// Returns [None] if there are no longer enough records to merge.
+async fn merge_records<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    account: &UserAddress,
+    asset: &AssetCode,
+    note: &PlannedTransfer,
+    fee: RecordAmount,
+) -> Result<Option<TransactionUID<EspressoLedger>>, TransferFailure>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let records = spendable_records(keystore, Some(account), asset).await;
+    if records.len() < 2 {
+        return Ok(None);
+    }
+    let merged = &records[..note.asset_inputs.min(records.len())];
+    let owner = merged[0].pub_key().clone();
+    // A native merge pays its own fee out of the merged records.
+    let amount = if note.native {
+        total(merged).saturating_sub(fee.as_u128())
+    } else {
+        total(merged)
+    };
+    if amount == 0 {
+        return Ok(None);
+    }
+
+    info!(
+        "merging {} records worth {} before paying receivers",
+        merged.len(),
+        sensitive(&amount)
+    );
+    transfer(
+        keystore,
+        Some(account),
+        asset,
+        &[(owner, RecordAmount::from(amount))],
+        fee,
+    )
+    .await
+    .map(Some)
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::{retry, FundedNetwork};
+    use primitive_types::U256;
+
+    #[async_std::test]
+    async fn test_transfer_many() {
+        let mut network = FundedNetwork::new(1).await;
+        let mut receiver = network.new_keystore().await;
+        let mut keys = Vec::new();
+        for i in 0..5 {
+            keys.push(
+                receiver
+                    .generate_sending_account(format!("receiver {}", i), None)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let faucet = network.faucet.address();
+        let receipts = transfer_many(
+            &mut network.keystore,
+            &faucet,
+            &AssetCode::native(),
+            keys.iter()
+                .map(|key| (key.clone(), RecordAmount::from(100u64)))
+                .collect(),
+            RecordAmount::from(1u64),
+        )
+        .await
+        .unwrap();
+        // Native transfers can pay 2 receivers each.
+        assert_eq!(receipts.len(), 3);
+        for receipt in &receipts {
+            assert!(matches!(
+                network.keystore.await_transaction(receipt).await.unwrap(),
+                TransactionStatus::Retired
+            ));
+        }
+
+        for key in &keys {
+            retry(|| async {
+                receiver
+                    .balance_breakdown(&key.address(), &AssetCode::native())
+                    .await
+                    == U256::from(100u64)
+            })
+            .await;
+        }
+
+        // Payouts are only planned from the paying account's own records, even if other accounts
+        // in the keystore could cover them.
+        let empty = network
+            .keystore
+            .generate_sending_account("empty".into(), None)
+            .await
+            .unwrap();
+        let err = transfer_many(
+            &mut network.keystore,
+            &empty.address(),
+            &AssetCode::native(),
+            vec![(keys[0].clone(), RecordAmount::from(100u64))],
+            RecordAmount::from(1u64),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.receipts.is_empty());
+        assert!(!err.maybe_submitted);
+    }
+}
@@ -0,0 +1,690 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Resilient connections to the EsQS.
+//!
+//! A long-running keystore depends on a single websocket subscription for all of its ledger
+//! events, and on occasional HTTP queries. A [QueryConnection] keeps a list of equivalent EsQS
+//! endpoints. Requests which fail are retried against the next endpoint, and an event subscription
+//! which drops is re-established, with exponential backoff, starting from the first event the
+//! keystore has not yet received. The keystore never sees the disconnect, only a delay. An endpoint
+//! which fails is avoided for a while, and then preferred again, so the connection returns to the
+//! primary endpoint once it recovers.
+//!
+//! Events can be received in one of two [SyncMode]s: pushed by the EsQS over a websocket, or polled
+//! over plain HTTP, for deployments where long-lived websocket connections are not available.
+
+use crate::redact::sensitive;
+use async_std::task::sleep;
+use espresso_core::ledger::EspressoLedger;
+use espresso_esqs::ApiError;
+use futures::prelude::*;
+use rand::Rng;
+use seahorse::{events::LedgerEvent, KeystoreError};
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use surf_disco::{Client, Error as _, StatusCode, Url};
+use tracing::{info, warn};
+
+/// How long to wait between attempts to reconnect.
+///
+/// The delay starts at `initial` and doubles after each consecutive failure, up to `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before reconnect attempt number `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .checked_mul(1u32 << attempt.min(31))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// How a [QueryConnection] receives ledger events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Subscribe to a stream of events pushed by the EsQS.
+    Push,
+    /// Poll the EsQS for new events.
+    Poll {
+        /// How long to wait before polling again once all available events have been received.
+        interval: Duration,
+        /// A random delay of up to this long is added to each `interval`, so that many keystores
+        /// started at the same time do not poll in lockstep.
+        jitter: Duration,
+        /// The maximum number of events to request at once while catching up.
+        batch_size: usize,
+    },
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        Self::Push
+    }
+}
+
+impl SyncMode {
+    /// Poll every `interval`, with a default jitter and batch size.
+    pub fn poll(interval: Duration) -> Self {
+        Self::Poll {
+            interval,
+            jitter: interval / 10,
+            batch_size: 100,
+        }
+    }
+}
+
+/// How far a keystore has synced with the ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// The number of blocks whose events have been delivered to the keystore.
+    pub synced_height: u64,
+    /// The number of blocks in the ledger, according to the EsQS.
+    pub ledger_height: u64,
+}
+
+impl SyncStatus {
+    /// The number of blocks the keystore has yet to receive.
+    pub fn lag(&self) -> u64 {
+        self.ledger_height.saturating_sub(self.synced_height)
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.lag() == 0
+    }
+}
+
+/// The state of a [QueryConnection].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStatus {
+    /// The endpoint most recently used.
+    pub endpoint: Url,
+    /// Whether the most recent request or subscription attempt succeeded.
+    pub connected: bool,
+    /// The number of times an event subscription has been re-established.
+    pub reconnects: u64,
+    /// The most recent error, if any.
+    pub last_error: Option<String>,
+}
+
+type EventStream =
+    Pin<Box<dyn Send + Stream<Item = Result<LedgerEvent<EspressoLedger>, ApiError>>>>;
+
+pub struct QueryConnection {
+    endpoints: Vec<(Url, Client<ApiError>)>,
+    // When each endpoint last failed, if it has not succeeded since.
+    failures: Mutex<Vec<Option<Instant>>>,
+    backoff: Backoff,
+    mode: SyncMode,
+    status: Mutex<ConnectionStatus>,
+    // The height of the last block delivered by an event subscription.
+    synced_height: AtomicU64,
+}
+
+impl Clone for QueryConnection {
+    /// A copy of this connection, with the same endpoints, settings, and state.
+    fn clone(&self) -> Self {
+        Self {
+            endpoints: self.endpoints.clone(),
+            failures: Mutex::new(self.failures.lock().unwrap().clone()),
+            backoff: self.backoff,
+            mode: self.mode,
+            status: Mutex::new(self.status()),
+            synced_height: AtomicU64::new(self.synced_height.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl QueryConnection {
+    /// Connect to the EsQS at any of `urls`, preferring them in order.
+    ///
+    /// Fails if `urls` is empty.
+    pub fn new(urls: Vec<Url>, backoff: Backoff) -> Result<Self, KeystoreError<EspressoLedger>> {
+        let endpoint = urls.first().cloned().ok_or_else(|| KeystoreError::Failed {
+            msg: "at least one EsQS endpoint is required".into(),
+        })?;
+        let mut conn = Self {
+            endpoints: Vec::new(),
+            failures: Default::default(),
+            backoff,
+            mode: SyncMode::default(),
+            status: Mutex::new(ConnectionStatus {
+                endpoint,
+                connected: false,
+                reconnects: 0,
+                last_error: None,
+            }),
+            synced_height: AtomicU64::new(0),
+        };
+        conn.add_fallbacks(urls);
+        Ok(conn)
+    }
+
+    /// Receive events according to `mode`.
+    pub fn with_sync_mode(mut self, mode: SyncMode) -> Self {
+        self.set_sync_mode(mode);
+        self
+    }
+
+    /// Receive events according to `mode` from now on.
+    ///
+    /// Event streams which are already open keep the mode they were opened with.
+    pub fn set_sync_mode(&mut self, mode: SyncMode) {
+        self.mode = mode;
+    }
+
+    /// Wait according to `backoff` between reconnection attempts from now on.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Fail over to `urls`, in order, after the endpoints already in use.
+    pub fn add_fallbacks(&mut self, urls: impl IntoIterator<Item = Url>) {
+        for url in urls {
+            let client = Client::builder(url.clone())
+                .set_timeout(Some(Duration::from_secs(5 * 60)))
+                .build();
+            self.endpoints.push((url, client));
+            self.failures.get_mut().unwrap().push(None);
+        }
+    }
+
+    pub fn urls(&self) -> Vec<Url> {
+        self.endpoints.iter().map(|(url, _)| url.clone()).collect()
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Record that the keystore has the state as of `height` blocks, for example from a snapshot.
+    pub(crate) fn set_synced_height(&self, height: u64) {
+        self.synced_height.fetch_max(height, Ordering::SeqCst);
+    }
+
+    /// The sync status of the keystore being served events by this connection.
+    pub async fn sync_status(&self) -> Result<SyncStatus, KeystoreError<EspressoLedger>> {
+        let latest_block: u64 = self.get("status/latest_block_id").await?;
+        Ok(SyncStatus {
+            synced_height: self.synced_height.load(Ordering::SeqCst),
+            ledger_height: latest_block + 1,
+        })
+    }
+
+    /// Wait until any endpoint is healthy, trying each for at most `timeout`.
+    pub async fn connect(&self, timeout: Duration) -> bool {
+        for i in self.candidates() {
+            let (url, client) = &self.endpoints[i];
+            if client.connect(Some(timeout)).await {
+                self.succeeded(i);
+                return true;
+            }
+            self.failed(i, format!("EsQS at {} is not healthy", url));
+        }
+        false
+    }
+
+    /// Send a GET request, failing over to each other endpoint in turn if it fails.
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        uri: &str,
+    ) -> Result<T, KeystoreError<EspressoLedger>> {
+        let mut last_error = None;
+        for i in self.candidates() {
+            let client = &self.endpoints[i].1;
+            match client.get::<T>(uri).send().await {
+                Ok(res) => {
+                    self.succeeded(i);
+                    return Ok(res);
+                }
+                Err(source) => last_error = Some(self.get_failed(i, uri, source)),
+            }
+        }
+        Err(KeystoreError::Failed {
+            msg: last_error.unwrap(),
+        })
+    }
+
+    /// Like [get](Self::get), but for queries which may ask for something which does not exist.
+    ///
+    /// If the EsQS answers that the request is invalid or its object was not found, this returns
+    /// [None] without failing over, since the endpoint is working.
+    pub async fn get_optional<T: DeserializeOwned>(
+        &self,
+        uri: &str,
+    ) -> Result<Option<T>, KeystoreError<EspressoLedger>> {
+        let mut last_error = None;
+        for i in self.candidates() {
+            let client = &self.endpoints[i].1;
+            match client.get::<T>(uri).send().await {
+                Ok(res) => {
+                    self.succeeded(i);
+                    return Ok(Some(res));
+                }
+                Err(source)
+                    if source.status() == StatusCode::BadRequest
+                        || source.status() == StatusCode::NotFound =>
+                {
+                    self.succeeded(i);
+                    return Ok(None);
+                }
+                Err(source) => last_error = Some(self.get_failed(i, uri, source)),
+            }
+        }
+        Err(KeystoreError::Failed {
+            msg: last_error.unwrap(),
+        })
+    }
+
+    /// Subscribe to ledger events from index `from` until (but not including) index `to`.
+    ///
+    /// If the subscription drops or yields an error, it is re-established from the first event
+    /// which has not yet been received, on the next endpoint, after backing off.
+    pub fn subscribe(
+        self: Arc<Self>,
+        from: usize,
+        to: Option<usize>,
+    ) -> Pin<Box<dyn Send + Stream<Item = LedgerEvent<EspressoLedger>>>> {
+        let conn = self.clone();
+        let mode = self.mode;
+        let events: Pin<Box<dyn Send + Stream<Item = _>>> = match mode {
+            SyncMode::Push => Box::pin(self.push_events(from, to)),
+            SyncMode::Poll {
+                interval,
+                jitter,
+                batch_size,
+            } => Box::pin(self.poll_events(from, to, interval, jitter, batch_size)),
+        };
+        Box::pin(events.inspect(move |event| {
+            if let LedgerEvent::Commit { block_id, .. } = event {
+                conn.synced_height.fetch_max(block_id + 1, Ordering::SeqCst);
+            }
+        }))
+    }
+
+    fn push_events(
+        self: Arc<Self>,
+        from: usize,
+        to: Option<usize>,
+    ) -> impl Stream<Item = LedgerEvent<EspressoLedger>> + Send {
+        stream::unfold(
+            (self, from, None::<(usize, EventStream)>, 0u32),
+            move |(conn, next, mut events, mut attempt)| async move {
+                if let Some(to) = to {
+                    if next >= to {
+                        return None;
+                    }
+                }
+                loop {
+                    let (i, stream) = match &mut events {
+                        Some((i, stream)) => (*i, stream),
+                        None => {
+                            if attempt > 0 {
+                                sleep(conn.backoff.delay(attempt - 1)).await;
+                            }
+                            if attempt > 0 || next > from {
+                                conn.status.lock().unwrap().reconnects += 1;
+                            }
+                            attempt += 1;
+                            events = conn.open(next).await;
+                            continue;
+                        }
+                    };
+                    match stream.next().await {
+                        Some(Ok(event)) => {
+                            return Some((event, (conn, next + 1, events, 0)));
+                        }
+                        Some(Err(err)) => {
+                            conn.failed(
+                                i,
+                                format!("error in EsQS event stream: {}", sensitive(&err)),
+                            );
+                        }
+                        None => {
+                            conn.failed(i, "EsQS event stream closed".to_string());
+                        }
+                    }
+                    // Drop the broken connection and fail over to the next endpoint.
+                    events = None;
+                }
+            },
+        )
+    }
+
+    fn poll_events(
+        self: Arc<Self>,
+        from: usize,
+        to: Option<usize>,
+        interval: Duration,
+        jitter: Duration,
+        batch_size: usize,
+    ) -> impl Stream<Item = LedgerEvent<EspressoLedger>> + Send {
+        stream::unfold(
+            (self, from, VecDeque::new(), 0u32),
+            move |(conn, next, mut buffer, mut attempt)| async move {
+                loop {
+                    if let Some(to) = to {
+                        if next >= to {
+                            return None;
+                        }
+                    }
+                    if let Some(event) = buffer.pop_front() {
+                        return Some((event, (conn, next + 1, buffer, attempt)));
+                    }
+
+                    let mut count = batch_size.max(1);
+                    if let Some(to) = to {
+                        count = count.min(to - next);
+                    }
+                    // `get` fails over between endpoints on its own; we only need to back off.
+                    match conn
+                        .get::<Vec<LedgerEvent<EspressoLedger>>>(&format!(
+                            "catchup/get_events_since/{}/{}",
+                            next, count
+                        ))
+                        .await
+                    {
+                        Ok(events) if !events.is_empty() => {
+                            buffer.extend(events);
+                            attempt = 0;
+                        }
+                        // We are caught up. Wait for new events.
+                        Ok(_) => sleep(interval + random_delay(jitter)).await,
+                        Err(_) => {
+                            sleep(conn.backoff.delay(attempt)).await;
+                            attempt += 1;
+                        }
+                    }
+                }
+            },
+        )
+    }
+
+    /// Subscribe to events from `from` at the first endpoint which accepts the subscription.
+    async fn open(&self, from: usize) -> Option<(usize, EventStream)> {
+        for i in self.candidates() {
+            let (url, client) = &self.endpoints[i];
+            info!("subscribing to events from {} at {}", from, url);
+            match client
+                .socket(&format!("catchup/subscribe_for_events/{}", from))
+                .subscribe::<LedgerEvent<EspressoLedger>>()
+                .await
+            {
+                Ok(events) => {
+                    self.succeeded(i);
+                    return Some((i, Box::pin(events)));
+                }
+                Err(err) => self.failed(
+                    i,
+                    format!(
+                        "failed to subscribe to events at {}: {}",
+                        url,
+                        sensitive(&err)
+                    ),
+                ),
+            }
+        }
+        None
+    }
+
+    /// The indices of the endpoints, in the order they should be tried.
+    ///
+    /// Endpoints are preferred in the order they were given, but an endpoint which has failed is
+    /// tried last until `backoff.max` has passed since it failed. After that it is preferred
+    /// again, so the connection returns to the primary endpoint once it recovers.
+    fn candidates(&self) -> Vec<usize> {
+        let failures = self.failures.lock().unwrap();
+        let (healthy, failing): (Vec<usize>, Vec<usize>) =
+            (0..self.endpoints.len()).partition(|i| match failures[*i] {
+                Some(failed) => failed.elapsed() >= self.backoff.max,
+                None => true,
+            });
+        healthy.into_iter().chain(failing).collect()
+    }
+
+    fn succeeded(&self, i: usize) {
+        self.failures.lock().unwrap()[i] = None;
+        let mut status = self.status.lock().unwrap();
+        status.endpoint = self.endpoints[i].0.clone();
+        status.connected = true;
+    }
+
+    /// Record that GET `uri` failed at endpoint `i`, returning the error message.
+    ///
+    /// The request and the error may identify the user, so they are redacted in the log and the
+    /// connection status, but not in the returned message.
+    fn get_failed(&self, i: usize, uri: &str, source: impl Display) -> String {
+        let url = &self.endpoints[i].0;
+        self.failed(
+            i,
+            format!(
+                "EsQS request GET {} to {} failed: {}",
+                sensitive(&uri),
+                url,
+                sensitive(&source)
+            ),
+        );
+        format!("EsQS request GET {} to {} failed: {}", uri, url, source)
+    }
+
+    fn failed(&self, i: usize, msg: String) {
+        warn!("{}", msg);
+        self.failures.lock().unwrap()[i] = Some(Instant::now());
+        let mut status = self.status.lock().unwrap();
+        status.endpoint = self.endpoints[i].0.clone();
+        status.connected = false;
+        status.last_error = Some(msg);
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max_micros = max.as_micros() as u64;
+    if max_micros == 0 {
+        Duration::ZERO
+    } else {
+        Duration::from_micros(rand::thread_rng().gen_range(0..=max_micros))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::TcpListener, task::spawn};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::atomic::AtomicBool;
+
+    /// A stand-in for an EsQS endpoint, which answers every request with `body` while it is up, and
+    /// drops every connection while it is down.
+    async fn fake_endpoint(body: &'static str) -> (Url, Arc<AtomicBool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let up = Arc::new(AtomicBool::new(true));
+        spawn({
+            let up = up.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    if !up.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // Read the request headers. The requests we expect have no body.
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                }
+            }
+        });
+        (url, up)
+    }
+
+    #[test]
+    fn test_no_endpoints() {
+        assert!(QueryConnection::new(vec![], Backoff::default()).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_failover() {
+        let (primary, primary_up) = fake_endpoint("1").await;
+        let (fallback, fallback_up) = fake_endpoint("2").await;
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(500),
+        };
+        let conn = QueryConnection::new(vec![primary.clone(), fallback.clone()], backoff).unwrap();
+        let get = || conn.get::<u64>("status/latest_block_id");
+
+        assert_eq!(get().await.unwrap(), 1);
+        assert_eq!(conn.status().endpoint, primary);
+
+        // When the primary fails, requests move to the fallback.
+        primary_up.store(false, Ordering::SeqCst);
+        assert_eq!(get().await.unwrap(), 2);
+        let status = conn.status();
+        assert_eq!(status.endpoint, fallback);
+        assert!(status.connected);
+
+        // A recovered primary is not used again until it has had time to settle...
+        primary_up.store(true, Ordering::SeqCst);
+        assert_eq!(get().await.unwrap(), 2);
+        // ...after which requests return to it.
+        sleep(backoff.max).await;
+        assert_eq!(get().await.unwrap(), 1);
+        assert_eq!(conn.status().endpoint, primary);
+
+        // If every endpoint fails, so does the request.
+        primary_up.store(false, Ordering::SeqCst);
+        fallback_up.store(false, Ordering::SeqCst);
+        assert!(get().await.is_err());
+        let status = conn.status();
+        assert!(!status.connected);
+        assert!(status.last_error.is_some());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1000), Duration::from_secs(1));
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod slow_tests {
+    use super::*;
+    use crate::testing::network::FundedNetwork;
+    use jf_cap::structs::AssetCode;
+    use seahorse::{ledger_state::TransactionStatus, RecordAmount};
+
+    async fn events(
+        network: &FundedNetwork,
+        mode: SyncMode,
+        from: usize,
+        to: usize,
+    ) -> Vec<Vec<u8>> {
+        let conn =
+            QueryConnection::new(vec![network.network.query_api.clone()], Backoff::default())
+                .unwrap()
+                .with_sync_mode(mode);
+        Arc::new(conn)
+            .subscribe(from, Some(to))
+            .map(|event| bincode::serialize(&event).unwrap())
+            .collect()
+            .await
+    }
+
+    #[async_std::test]
+    async fn test_poll_matches_push() {
+        let mut network = FundedNetwork::new(4).await;
+        let mut receiver = network.new_keystore().await;
+        let receiver_key = receiver
+            .generate_sending_account("receiver".into(), None)
+            .await
+            .unwrap();
+        let faucet = network.faucet.address();
+        // Each transfer commits a block of its own, so together with the genesis block there are
+        // at least 4 events.
+        for _ in 0..3 {
+            let receipt = network
+                .keystore
+                .transfer(
+                    Some(&faucet),
+                    &AssetCode::native(),
+                    &[(receiver_key.clone(), RecordAmount::from(10u64))],
+                    RecordAmount::from(1u64),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                network.keystore.await_transaction(&receipt).await.unwrap(),
+                TransactionStatus::Retired
+            ));
+        }
+
+        let pushed = events(&network, SyncMode::Push, 0, 4).await;
+        assert_eq!(pushed.len(), 4);
+        // Page sizes which do and do not divide the number of events, so that pages end both at
+        // and before the end of the requested range.
+        for batch_size in [1, 3, 4, 100] {
+            let mode = SyncMode::Poll {
+                interval: Duration::from_millis(100),
+                jitter: Duration::ZERO,
+                batch_size,
+            };
+            assert_eq!(events(&network, mode, 0, 4).await, pushed, "{}", batch_size);
+            assert_eq!(
+                events(&network, mode, 1, 4).await,
+                pushed[1..],
+                "{}",
+                batch_size
+            );
+        }
+    }
+}
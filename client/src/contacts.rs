@@ -0,0 +1,266 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Named contacts.
+//!
+//! A [ContactBook] maps human-chosen names to the addresses of counterparties, so that users can
+//! refer to the people they pay by name rather than by address. The keystore APIs take addresses,
+//! so names are turned into addresses with [resolve](ContactBook::resolve); the CLI does this for
+//! the address arguments of its contact commands, such as `pay`. The CLI keeps its contact book in
+//! its keystore's backend, using
+//! [NetworkBackend::with_contact_book](crate::network::NetworkBackend::with_contact_book).
+//!
+//! The contact book is persisted in a single file, which is typically stored alongside the
+//! keystore. It can also be exported to and imported from a versioned JSON format, so that contacts
+//! can be moved between keystores and applications:
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "contacts": [
+//!         { "name": "alice", "address": "ADDR~...", "label": "landlord" }
+//!     ]
+//! }
+//! ```
+
+use crate::{persistence, EspressoKeystoreError};
+use jf_cap::keys::UserAddress;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A saved counterparty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub address: UserAddress,
+    /// A free-form description of the contact.
+    pub label: String,
+}
+
+/// The version of the contact interchange format produced by
+/// [export_interchange](ContactBook::export_interchange).
+pub const CONTACT_INTERCHANGE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ContactInterchange {
+    version: u32,
+    contacts: Vec<InterchangeContact>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InterchangeContact {
+    name: String,
+    address: UserAddress,
+    #[serde(default)]
+    label: String,
+}
+
+/// A persistent collection of [Contact]s, indexed by name.
+#[derive(Debug)]
+pub struct ContactBook {
+    path: PathBuf,
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl ContactBook {
+    /// Load the contact book stored at `path`.
+    ///
+    /// If `path` does not exist, the contact book is empty, and it will be created the first time
+    /// it is modified.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, EspressoKeystoreError> {
+        let path = path.into();
+        let contacts = persistence::load(&path, "contact book")?;
+        Ok(Self { path, contacts })
+    }
+
+    /// Add a contact, or update the address and label of an existing contact.
+    ///
+    /// Names which are themselves valid addresses are not allowed, since they would make
+    /// [resolve](Self::resolve) ambiguous.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        address: UserAddress,
+        label: impl Into<String>,
+    ) -> Result<(), EspressoKeystoreError> {
+        let name = name.into();
+        Self::validate_name(&name)?;
+        self.insert(name, address, label.into());
+        self.save()
+    }
+
+    /// Export all contacts in the JSON interchange format.
+    pub fn export_interchange(&self) -> Result<String, EspressoKeystoreError> {
+        let interchange = ContactInterchange {
+            version: CONTACT_INTERCHANGE_VERSION,
+            contacts: self
+                .contacts
+                .values()
+                .map(|contact| InterchangeContact {
+                    name: contact.name.clone(),
+                    address: contact.address.clone(),
+                    label: contact.label.clone(),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&interchange).map_err(|err| Self::error("export", err))
+    }
+
+    /// Import contacts from the JSON interchange format, returning the number imported.
+    ///
+    /// Imported contacts replace existing contacts with the same name. The whole document is
+    /// validated before anything is imported, so if it is malformed, uses an unsupported version,
+    /// or contains an invalid or repeated name, an error is returned and the contact book is not
+    /// changed.
+    pub fn import_interchange(&mut self, json: &str) -> Result<usize, EspressoKeystoreError> {
+        let interchange: ContactInterchange =
+            serde_json::from_str(json).map_err(|err| Self::error("import", err))?;
+        if interchange.version != CONTACT_INTERCHANGE_VERSION {
+            return Err(Self::error(
+                "import",
+                format!("unsupported version {}", interchange.version),
+            ));
+        }
+        let mut names = HashSet::new();
+        for contact in &interchange.contacts {
+            Self::validate_name(&contact.name)?;
+            if !names.insert(&contact.name) {
+                return Err(Self::error(
+                    "import",
+                    format!("duplicate contact name {:?}", contact.name),
+                ));
+            }
+        }
+
+        let count = interchange.contacts.len();
+        for contact in interchange.contacts {
+            self.insert(contact.name, contact.address, contact.label);
+        }
+        self.save()?;
+        Ok(count)
+    }
+
+    /// Remove a contact, returning it if it existed.
+    pub fn remove(&mut self, name: &str) -> Result<Option<Contact>, EspressoKeystoreError> {
+        let contact = self.contacts.remove(name);
+        if contact.is_some() {
+            self.save()?;
+        }
+        Ok(contact)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contact> {
+        self.contacts.get(name)
+    }
+
+    /// Find the contact with the given address, if there is one.
+    pub fn find(&self, address: &UserAddress) -> Option<&Contact> {
+        self.contacts
+            .values()
+            .find(|contact| contact.address == *address)
+    }
+
+    /// All contacts, ordered by name.
+    pub fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        self.contacts.values()
+    }
+
+    /// Interpret `name_or_address` as either the name of a contact or an address.
+    pub fn resolve(&self, name_or_address: &str) -> Result<UserAddress, EspressoKeystoreError> {
+        if let Some(contact) = self.contacts.get(name_or_address) {
+            return Ok(contact.address.clone());
+        }
+        UserAddress::from_str(name_or_address).map_err(|_| EspressoKeystoreError::Failed {
+            msg: format!("{} is neither a contact nor an address", name_or_address),
+        })
+    }
+
+    fn validate_name(name: &str) -> Result<(), EspressoKeystoreError> {
+        if name.is_empty() || UserAddress::from_str(name).is_ok() {
+            return Err(EspressoKeystoreError::Failed {
+                msg: format!("invalid contact name {:?}", name),
+            });
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, name: String, address: UserAddress, label: String) {
+        self.contacts.insert(
+            name.clone(),
+            Contact {
+                name,
+                address,
+                label,
+            },
+        );
+    }
+
+    fn save(&self) -> Result<(), EspressoKeystoreError> {
+        persistence::save(&self.path, &self.contacts, "contact book")
+    }
+
+    fn error(action: &str, err: impl std::fmt::Display) -> EspressoKeystoreError {
+        persistence::error(action, "contact book", err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jf_cap::keys::UserKeyPair;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_contact_book_persistence() {
+        let dir = TempDir::new("contact_book").unwrap();
+        let path = dir.path().join("contacts");
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let alice = UserKeyPair::generate(&mut rng).address();
+        let bob = UserKeyPair::generate(&mut rng).address();
+
+        let mut book = ContactBook::load(&path).unwrap();
+        book.add("alice", alice.clone(), "landlord").unwrap();
+        book.add("bob", bob.clone(), "").unwrap();
+        // Names that parse as addresses are ambiguous.
+        assert!(book.add(bob.to_string(), alice.clone(), "").is_err());
+        assert_eq!(book.remove("bob").unwrap().unwrap().address, bob);
+
+        let book = ContactBook::load(&path).unwrap();
+        assert_eq!(book.contacts().count(), 1);
+        assert_eq!(book.get("alice").unwrap().label, "landlord");
+        assert_eq!(book.resolve("alice").unwrap(), alice);
+        assert_eq!(book.resolve(&bob.to_string()).unwrap(), bob);
+        assert!(book.resolve("bob").is_err());
+    }
+
+    #[test]
+    fn test_contact_interchange() {
+        let dir = TempDir::new("contact_interchange").unwrap();
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let alice = UserKeyPair::generate(&mut rng).address();
+        let bob = UserKeyPair::generate(&mut rng).address();
+
+        let mut book = ContactBook::load(dir.path().join("old")).unwrap();
+        book.add("alice", alice.clone(), "landlord").unwrap();
+        book.add("bob", bob.clone(), "").unwrap();
+        let json = book.export_interchange().unwrap();
+
+        let mut imported = ContactBook::load(dir.path().join("new")).unwrap();
+        assert_eq!(imported.import_interchange(&json).unwrap(), 2);
+        assert_eq!(imported.get("alice").unwrap().address, alice);
+        assert_eq!(imported.get("alice").unwrap().label, "landlord");
+        assert_eq!(imported.get("bob").unwrap().address, bob);
+
+        // Invalid documents are rejected without changing the contact book.
+        let future = json.replacen("\"version\": 1", "\"version\": 2", 1);
+        assert!(imported.import_interchange(&future).is_err());
+        let ambiguous = json.replacen("\"bob\"", &format!("{:?}", alice.to_string()), 1);
+        assert!(imported.import_interchange(&ambiguous).is_err());
+        assert_eq!(imported.contacts().count(), 2);
+    }
+}
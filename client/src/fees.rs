@@ -0,0 +1,205 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Management of native fee records.
+//!
+//! Every transaction pays its fee from a single native record and returns the rest as change, so
+//! over time a busy keystore accumulates many small native records. A record worth less than the
+//! fee can never pay a fee on its own, and once every record is that small the keystore can no
+//! longer transact, even if its total native balance is large. [fee_budget] reports how far a
+//! keystore is from that point, and [consolidate_fee_records] merges records to move it back.
+
+use crate::{
+    spendable::{spendable_records, total},
+    EspressoKeystore, EspressoKeystoreError,
+};
+use espresso_core::{ledger::EspressoLedger, transfer_plan::max_asset_inputs};
+use jf_cap::{keys::UserAddress, structs::AssetCode};
+use seahorse::{ledger_state::TransactionUID, KeystoreBackend, RecordAmount};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The ability of an account to pay fees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeBudget {
+    /// The fee the budget was computed for.
+    pub fee: u128,
+    /// The number of spendable native records.
+    pub records: usize,
+    /// The number of those records which are too small to pay a fee on their own.
+    pub dust_records: usize,
+    /// The total value of the spendable native records.
+    pub total: u128,
+    /// The number of transactions paying the fee which these records can fund without being
+    /// consolidated, or [None] if the fee is zero.
+    pub transactions: Option<u128>,
+}
+
+impl FeeBudget {
+    /// Whether consolidating records would let the account fund more transactions.
+    pub fn is_fragmented(&self) -> bool {
+        match self.transactions {
+            Some(transactions) => transactions < self.total / self.fee,
+            None => false,
+        }
+    }
+}
+
+/// Report how many transactions paying `fee` the native records of `account` can fund.
+///
+/// If `account` is [None], all of the keystore's sending accounts are included.
+pub async fn fee_budget<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    account: Option<&UserAddress>,
+    fee: RecordAmount,
+) -> FeeBudget
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let records = spendable_records(keystore, account, &AssetCode::native()).await;
+    let amounts: Vec<u128> = records
+        .iter()
+        .map(|record| record.amount().as_u128())
+        .collect();
+    let fee = fee.as_u128();
+    FeeBudget {
+        fee,
+        records: amounts.len(),
+        dust_records: amounts.iter().filter(|amount| **amount < fee).count(),
+        total: total(&records),
+        // A record worth `amount` pays `amount / fee` fees one after another, each transaction
+        // returning the rest as change for the next.
+        transactions: if fee == 0 {
+            None
+        } else {
+            Some(saturating_sum(amounts.iter().map(|amount| amount / fee)))
+        },
+    }
+}
+
+/// Merge native records of `account` into one, paying `fee`.
+///
+/// A transfer can only spend a few records, and the keystore spends the largest records first. If
+/// all of the spendable native records fit in one transfer, their whole value, less the fee, is
+/// transferred back to `account`, which replaces them with a single record. Otherwise the largest
+/// records which fit are merged in the same way, which leaves the smaller records for later: each
+/// call reduces the number of records, so calling this repeatedly, waiting for each transfer to
+/// complete, eventually merges every record. Returns [None] if there is nothing worth merging:
+/// fewer than two records, or records whose combined value does not cover the fee.
+pub async fn consolidate_fee_records<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    account: &UserAddress,
+    fee: RecordAmount,
+) -> Result<Option<TransactionUID<EspressoLedger>>, EspressoKeystoreError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let pub_key = match keystore
+        .sending_keys()
+        .await
+        .into_iter()
+        .find(|key| key.address() == *account)
+    {
+        Some(key) => key.pub_key(),
+        None => {
+            return Err(EspressoKeystoreError::Failed {
+                msg: format!("{} is not a sending account of this keystore", account),
+            })
+        }
+    };
+
+    let records = spendable_records(keystore, Some(account), &AssetCode::native()).await;
+    let max_inputs = max_asset_inputs(true, 1).unwrap_or(0);
+    if records.len() < 2 || max_inputs < 2 {
+        return Ok(None);
+    }
+    // The records are sorted largest first, which is the order the keystore spends them in, so a
+    // transfer of the combined value of a prefix spends exactly that prefix.
+    let merged = total(&records[..max_inputs.min(records.len())]);
+    let amount = match merged.checked_sub(fee.as_u128()) {
+        Some(amount) if amount > 0 => amount,
+        _ => return Ok(None),
+    };
+    let receipt = keystore
+        .transfer(
+            Some(account),
+            &AssetCode::native(),
+            &[(pub_key, RecordAmount::from(amount))],
+            fee,
+        )
+        .await?;
+    Ok(Some(receipt))
+}
+
+/// Sum amounts without overflowing.
+///
+/// Each record amount fits in a `u128`, but the sum of many records need not. A saturated total
+/// still covers any fee.
+fn saturating_sum(amounts: impl IntoIterator<Item = u128>) -> u128 {
+    amounts
+        .into_iter()
+        .fold(0u128, |total, amount| total.saturating_add(amount))
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::FundedNetwork;
+    use seahorse::ledger_state::TransactionStatus;
+
+    #[async_std::test]
+    async fn test_consolidate_fee_records() {
+        let mut network = FundedNetwork::new(4).await;
+        let keystore = &mut network.keystore;
+        let faucet = network.faucet.address();
+        let account = keystore
+            .generate_sending_account("fees".into(), None)
+            .await
+            .unwrap();
+        let fee = RecordAmount::from(1u64);
+        for _ in 0..5 {
+            let receipt = keystore
+                .transfer(
+                    Some(&faucet),
+                    &AssetCode::native(),
+                    &[(account.clone(), RecordAmount::from(100u64))],
+                    fee,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                keystore.await_transaction(&receipt).await.unwrap(),
+                TransactionStatus::Retired
+            ));
+        }
+        let budget = fee_budget(keystore, Some(&account.address()), fee).await;
+        assert_eq!(budget.records, 5);
+        assert_eq!(budget.total, 500);
+
+        // 5 records do not fit in one transfer, so the first merge spends 3 of them, and the second
+        // merges the rest.
+        for records in [3, 1] {
+            let receipt = consolidate_fee_records(keystore, &account.address(), fee)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                keystore.await_transaction(&receipt).await.unwrap(),
+                TransactionStatus::Retired
+            ));
+            assert_eq!(
+                fee_budget(keystore, Some(&account.address()), fee)
+                    .await
+                    .records,
+                records
+            );
+        }
+        let budget = fee_budget(keystore, Some(&account.address()), fee).await;
+        assert_eq!(budget.total, 498);
+        assert!(consolidate_fee_records(keystore, &account.address(), fee)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
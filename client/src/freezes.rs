@@ -0,0 +1,224 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Visibility into records frozen by a third party.
+//!
+//! The freezer of an asset can freeze any record of that asset, after which its owner cannot spend
+//! it until the freezer unfreezes it. The keystore learns about freezes from the ledger like any
+//! other transaction, but nothing tells the owner that part of their balance just became
+//! unspendable. [frozen_records] lists the records of an asset which are currently frozen, and a
+//! [FreezeWatcher] reports each record which is frozen or unfrozen.
+
+use crate::EspressoKeystore;
+use espresso_core::ledger::EspressoLedger;
+use jf_cap::{
+    keys::UserAddress,
+    structs::{AssetCode, FreezeFlag, RecordCommitment},
+};
+use seahorse::{records::Record, KeystoreBackend};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// A record owned by the keystore's sending accounts was frozen or unfrozen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FreezeEvent {
+    /// The record with `commitment`, holding `amount` of `asset`, was frozen.
+    Frozen {
+        commitment: RecordCommitment,
+        asset: AssetCode,
+        amount: u128,
+    },
+    /// The frozen record with `commitment`, holding `amount` of `asset`, was unfrozen, and the
+    /// amount can be spent again.
+    ///
+    /// Unfreezing replaces the frozen record with a new, unfrozen one, so `commitment` identifies
+    /// the record reported by the corresponding [Frozen](FreezeEvent::Frozen) event.
+    Unfrozen {
+        commitment: RecordCommitment,
+        asset: AssetCode,
+        amount: u128,
+    },
+}
+
+/// The frozen records of `asset` owned by the keystore's sending accounts.
+pub async fn frozen_records<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    asset: &AssetCode,
+) -> Vec<Record>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    owned_frozen_records(keystore)
+        .await
+        .into_iter()
+        .filter(|record| record.asset_code() == *asset)
+        .collect()
+}
+
+/// Detects freezes and unfreezes of the keystore's records.
+///
+/// The watcher remembers the commitments of the frozen records it saw at the last
+/// [poll](Self::poll), and reports each record which has been frozen or unfrozen since. Freezing
+/// or unfreezing a record replaces it with a new record with a different commitment, so a record
+/// frozen and another unfrozen between two polls are both reported, even if they hold the same
+/// amount.
+#[derive(Clone, Debug, Default)]
+pub struct FreezeWatcher {
+    frozen: HashMap<RecordCommitment, (AssetCode, u128)>,
+}
+
+impl FreezeWatcher {
+    /// Start watching from the keystore's current state.
+    ///
+    /// Records which are already frozen are not reported.
+    pub async fn new<'a, Backend, Meta>(keystore: &EspressoKeystore<'a, Backend, Meta>) -> Self
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        Self {
+            frozen: frozen_by_commitment(keystore).await,
+        }
+    }
+
+    /// The total frozen amount of each asset as of the last poll.
+    pub fn frozen_balances(&self) -> HashMap<AssetCode, u128> {
+        let mut balances = HashMap::new();
+        for (asset, amount) in self.frozen.values() {
+            let balance = balances.entry(*asset).or_insert(0u128);
+            *balance = balance.saturating_add(*amount);
+        }
+        balances
+    }
+
+    /// Report the freezes and unfreezes which the keystore has seen since the last poll.
+    pub async fn poll<'a, Backend, Meta>(
+        &mut self,
+        keystore: &EspressoKeystore<'a, Backend, Meta>,
+    ) -> Vec<FreezeEvent>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        let frozen = frozen_by_commitment(keystore).await;
+        let events = diff(&self.frozen, &frozen);
+        self.frozen = frozen;
+        events
+    }
+}
+
+/// The records which were frozen or unfrozen between two sets of frozen records.
+fn diff(
+    before: &HashMap<RecordCommitment, (AssetCode, u128)>,
+    after: &HashMap<RecordCommitment, (AssetCode, u128)>,
+) -> Vec<FreezeEvent> {
+    let frozen = after
+        .iter()
+        .filter(|(commitment, _)| !before.contains_key(commitment))
+        .map(|(commitment, (asset, amount))| FreezeEvent::Frozen {
+            commitment: *commitment,
+            asset: *asset,
+            amount: *amount,
+        });
+    let unfrozen = before
+        .iter()
+        .filter(|(commitment, _)| !after.contains_key(commitment))
+        .map(|(commitment, (asset, amount))| FreezeEvent::Unfrozen {
+            commitment: *commitment,
+            asset: *asset,
+            amount: *amount,
+        });
+    frozen.chain(unfrozen).collect()
+}
+
+async fn frozen_by_commitment<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+) -> HashMap<RecordCommitment, (AssetCode, u128)>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    owned_frozen_records(keystore)
+        .await
+        .into_iter()
+        .map(|record| {
+            (
+                RecordCommitment::from(&record.record_opening()),
+                (record.asset_code(), record.amount().as_u128()),
+            )
+        })
+        .collect()
+}
+
+async fn owned_frozen_records<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+) -> Vec<Record>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    // The keystore also tracks records it can view or freeze but does not own.
+    let owners: HashSet<UserAddress> = keystore
+        .sending_keys()
+        .await
+        .into_iter()
+        .map(|key| key.address())
+        .collect();
+    keystore
+        .records()
+        .await
+        .into_iter()
+        .filter(|record| {
+            record.freeze_flag() == FreezeFlag::Frozen
+                && owners.contains(&record.pub_key().address())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jf_cap::{
+        keys::UserKeyPair,
+        structs::{Amount, AssetDefinition, RecordOpening},
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_diff_frozen_records() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let asset = AssetCode::native();
+        let mut frozen_record = || {
+            let ro = RecordOpening::new(
+                &mut rng,
+                Amount::from(100u64),
+                AssetDefinition::native(),
+                owner.clone(),
+                FreezeFlag::Frozen,
+            );
+            (RecordCommitment::from(&ro), (asset, 100u128))
+        };
+        let (a, b, c) = (frozen_record(), frozen_record(), frozen_record());
+
+        let before: HashMap<_, _> = vec![a, b].into_iter().collect();
+        assert!(diff(&before, &before).is_empty());
+
+        // `b` is unfrozen and `c`, of the same amount, is frozen. The frozen total does not change,
+        // but both records are reported.
+        let after: HashMap<_, _> = vec![a, c].into_iter().collect();
+        let events = diff(&before, &after);
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&FreezeEvent::Frozen {
+            commitment: c.0,
+            asset,
+            amount: 100
+        }));
+        assert!(events.contains(&FreezeEvent::Unfrozen {
+            commitment: b.0,
+            asset,
+            amount: 100
+        }));
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Extension points for custom logic around keystore operations.
+//!
+//! A [BackendHook] registered on a [NetworkBackend](crate::network::NetworkBackend) is called
+//! before every transaction is submitted, after every submitted transaction is finalized, and for
+//! every ledger event the keystore receives. Hooks can be used for compliance checks, webhooks,
+//! accounting, and similar integrations, without wrapping every keystore method. A hook can veto a
+//! submission by returning an error from [before_submit](BackendHook::before_submit), in which
+//! case the transaction is never sent to the validator and the keystore reports the error.
+
+use crate::EspressoKeystoreError;
+use async_trait::async_trait;
+use espresso_core::{ledger::EspressoLedger, state::ElaboratedTransaction};
+use seahorse::{events::LedgerEvent, transactions::Transaction};
+
+/// Callbacks invoked by the network backend.
+///
+/// Every method has a default implementation which does nothing, so hooks only need to implement
+/// the callbacks they care about. Hooks are called in the order they were registered.
+#[async_trait]
+pub trait BackendHook: Send + Sync {
+    /// Called before `txn` is submitted to the validator.
+    ///
+    /// `info` is the keystore's record of the transaction. Returning an error vetoes the
+    /// submission, and no later hooks are called.
+    async fn before_submit(
+        &self,
+        _txn: &ElaboratedTransaction,
+        _info: &Transaction<EspressoLedger>,
+    ) -> Result<(), EspressoKeystoreError> {
+        Ok(())
+    }
+
+    /// Called when a submitted transaction is included in the block `block_id`, at index
+    /// `txn_id`.
+    async fn after_accept(
+        &self,
+        _info: &Transaction<EspressoLedger>,
+        _block_id: u64,
+        _txn_id: u64,
+    ) {
+    }
+
+    /// Called when a submitted transaction is rejected or expires.
+    async fn after_reject(&self, _info: &Transaction<EspressoLedger>) {}
+
+    /// Called for every ledger event, before it is delivered to the keystore.
+    ///
+    /// Records received by the keystore arrive in
+    /// [Memos](seahorse::events::LedgerEvent::Memos) events.
+    async fn on_event(&self, _event: &LedgerEvent<EspressoLedger>) {}
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::{retry, FundedNetwork, TestKeystore};
+    use async_std::sync::Arc;
+    use jf_cap::{
+        keys::{UserKeyPair, UserPubKey},
+        structs::AssetCode,
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use seahorse::{
+        ledger_state::{TransactionStatus, TransactionUID},
+        RecordAmount,
+    };
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct TestHook {
+        veto: AtomicBool,
+        submitted: AtomicUsize,
+        accepted: AtomicUsize,
+        events: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BackendHook for TestHook {
+        async fn before_submit(
+            &self,
+            _txn: &ElaboratedTransaction,
+            _info: &Transaction<EspressoLedger>,
+        ) -> Result<(), EspressoKeystoreError> {
+            if self.veto.load(Ordering::SeqCst) {
+                return Err(EspressoKeystoreError::Failed {
+                    msg: "vetoed".into(),
+                });
+            }
+            self.submitted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn after_accept(
+            &self,
+            _info: &Transaction<EspressoLedger>,
+            _block_id: u64,
+            _txn_id: u64,
+        ) {
+            self.accepted.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn on_event(&self, _event: &LedgerEvent<EspressoLedger>) {
+            self.events.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    async fn pay(
+        keystore: &mut TestKeystore,
+        receiver: &UserPubKey,
+    ) -> Result<TransactionUID<EspressoLedger>, EspressoKeystoreError> {
+        keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(receiver.clone(), RecordAmount::from(100u64))],
+                RecordAmount::from(1u64),
+            )
+            .await
+    }
+
+    #[async_std::test]
+    async fn test_hooks() {
+        let mut network = FundedNetwork::new(6).await;
+        let hook = Arc::new(TestHook::default());
+        let backend = network.backend().await.with_hook(hook.clone());
+        let mut keystore = network.new_keystore_with_backend(backend).await;
+        keystore
+            .add_account(network.faucet.clone(), "faucet".into(), Default::default())
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&network.faucet.address())
+            .await
+            .unwrap();
+        // Scanning for the faucet record delivered at least the genesis block.
+        assert!(hook.events.load(Ordering::SeqCst) > 0);
+
+        let receiver = UserKeyPair::generate(&mut ChaChaRng::from_seed([6; 32])).pub_key();
+        let receipt = pay(&mut keystore, &receiver).await.unwrap();
+        assert!(matches!(
+            keystore.await_transaction(&receipt).await.unwrap(),
+            TransactionStatus::Retired
+        ));
+        assert_eq!(hook.submitted.load(Ordering::SeqCst), 1);
+        retry(|| async { hook.accepted.load(Ordering::SeqCst) == 1 }).await;
+
+        // A vetoed transaction is never submitted.
+        hook.veto.store(true, Ordering::SeqCst);
+        assert!(pay(&mut keystore, &receiver).await.is_err());
+        assert_eq!(hook.submitted.load(Ordering::SeqCst), 1);
+    }
+}
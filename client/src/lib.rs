@@ -1,10 +1,28 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+pub mod annotations;
+pub mod batch;
 pub mod cli_client;
+pub mod connection;
+pub mod contacts;
+pub mod fees;
+pub mod freezes;
+pub mod hooks;
+pub mod metrics;
 pub mod network;
+mod persistence;
+pub mod policy;
+pub mod prover_keys;
+mod redact;
+pub mod rotation;
+pub mod scheduler;
+mod spendable;
+pub mod submit_queue;
 #[cfg(any(test, feature = "testing"))]
 pub mod testing;
+pub mod tracking;
+mod transfer;
 
 pub use cli_client::CliClient;
 pub use seahorse::*;
@@ -12,15 +12,24 @@ mod cli_client;
 
 use async_trait::async_trait;
 use clap::Parser;
-use espresso_client::network::NetworkBackend;
+use espresso_client::{
+    contacts::ContactBook,
+    network::{NetworkBackend, NetworkConfig},
+    EspressoKeystore,
+};
 use espresso_core::ledger::EspressoLedger;
-use jf_cap::proof::UniversalParam;
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    proof::UniversalParam,
+    structs::AssetCode,
+};
 use seahorse::{
     cli::*,
+    cli_writeln, command,
     io::SharedIO,
     loader::{InteractiveLoader, MnemonicPasswordLogin},
     reader::Reader,
-    KeystoreError,
+    KeystoreBackend, KeystoreError, RecordAmount,
 };
 use std::path::PathBuf;
 use std::process::exit;
@@ -62,6 +71,10 @@ pub struct Args {
     )]
     pub esqs_url: Url,
 
+    /// URLs for other Espresso Query Services to fail over to if the main one is unavailable.
+    #[arg(long, env = "ESPRESSO_ESQS_FALLBACK_URLS", value_delimiter = ',')]
+    pub esqs_fallback_url: Vec<Url>,
+
     /// URL for the Espresso address book.
     #[arg(
         long,
@@ -77,6 +90,22 @@ pub struct Args {
         default_value = "http://localhost:50089"
     )]
     pub submit_url: Url,
+
+    /// Directory in which to cache proving keys.
+    ///
+    /// If not given, proving keys are preprocessed every time the keystore starts.
+    #[arg(long, env = "ESPRESSO_PROVER_KEY_DIR")]
+    pub prover_key_dir: Option<PathBuf>,
+
+    /// URL from which to download proving keys which are not cached.
+    #[arg(long, env = "ESPRESSO_PROVER_KEY_URL")]
+    pub prover_key_url: Option<Url>,
+
+    /// Path to the contact book used by the contact commands.
+    ///
+    /// If not given, contacts are stored in ~/.translucence/contacts.
+    #[arg(long, env = "ESPRESSO_CONTACTS_PATH")]
+    pub contacts: Option<PathBuf>,
 }
 
 impl CLIArgs for Args {
@@ -103,6 +132,136 @@ impl CLIArgs for Args {
 
 struct EspressoCli;
 
+type CliKeystore<'a> = EspressoKeystore<'a, NetworkBackend<'a>, MnemonicPasswordLogin>;
+
+// The contact commands only have access to the keystore, so the contact book is loaded with the
+// backend and reached through the keystore.
+async fn with_contacts<'a, T>(
+    keystore: &CliKeystore<'a>,
+    f: impl FnOnce(&mut ContactBook) -> Result<T, KeystoreError<EspressoLedger>>,
+) -> Result<T, KeystoreError<EspressoLedger>> {
+    let state = keystore.read().await;
+    let mut contacts = match state.backend().contact_book().await {
+        Some(contacts) => contacts,
+        None => {
+            return Err(KeystoreError::Failed {
+                msg: "contact book is not loaded".into(),
+            })
+        }
+    };
+    f(&mut contacts)
+}
+
+async fn resolve_public_key(
+    keystore: &CliKeystore<'_>,
+    name_or_address: &str,
+) -> Result<UserPubKey, KeystoreError<EspressoLedger>> {
+    let address = with_contacts(keystore, |contacts| contacts.resolve(name_or_address)).await?;
+    keystore
+        .read()
+        .await
+        .backend()
+        .get_public_key(&address)
+        .await
+}
+
+fn contact_commands<'a>() -> Vec<Command<'a, EspressoCli>> {
+    vec![
+        command!(
+            contacts,
+            "list saved contacts",
+            EspressoCli,
+            |io, keystore| {
+                match with_contacts(keystore, |contacts| {
+                    Ok(contacts
+                        .contacts()
+                        .map(|contact| {
+                            format!("{} {} {}", contact.name, contact.address, contact.label)
+                        })
+                        .collect::<Vec<_>>())
+                })
+                .await
+                {
+                    Ok(lines) => {
+                        for line in lines {
+                            cli_writeln!(io, "{}", line);
+                        }
+                    }
+                    Err(err) => cli_writeln!(io, "{}", err),
+                }
+            }
+        ),
+        command!(
+            add_contact,
+            "save the address of a counterparty under a name",
+            EspressoCli,
+            |io, keystore, name: String, address: UserAddress; label: Option<String>| {
+                match with_contacts(keystore, |contacts| {
+                    contacts.add(name, address, label.unwrap_or_default())
+                })
+                .await
+                {
+                    Ok(()) => cli_writeln!(io, "Contact saved."),
+                    Err(err) => cli_writeln!(io, "{}", err),
+                }
+            }
+        ),
+        command!(
+            remove_contact,
+            "remove a saved contact",
+            EspressoCli,
+            |io, keystore, name: String| {
+                match with_contacts(keystore, |contacts| contacts.remove(&name)).await {
+                    Ok(Some(_)) => cli_writeln!(io, "Contact removed."),
+                    Ok(None) => cli_writeln!(io, "No contact named {}.", name),
+                    Err(err) => cli_writeln!(io, "{}", err),
+                }
+            }
+        ),
+        command!(
+            pay,
+            "transfer owned assets to another user, by contact name or address",
+            EspressoCli,
+            |io,
+             keystore,
+             asset: AssetCode,
+             from: String,
+             to: String,
+             amount: u64,
+             fee: u64;
+             wait: Option<bool>| {
+                let result = async {
+                    let from = with_contacts(keystore, |contacts| contacts.resolve(&from)).await?;
+                    let to = resolve_public_key(keystore, &to).await?;
+                    keystore
+                        .transfer(
+                            Some(&from),
+                            &asset,
+                            &[(to, RecordAmount::from(amount))],
+                            RecordAmount::from(fee),
+                        )
+                        .await
+                }
+                .await;
+                match result {
+                    Ok(receipt) if wait == Some(true) => {
+                        match keystore.await_transaction(&receipt).await {
+                            Ok(status) => cli_writeln!(io, "{}", status),
+                            Err(err) => cli_writeln!(
+                                io,
+                                "Error waiting for transaction to complete: {}",
+                                err
+                            ),
+                        }
+                    }
+                    Ok(receipt) => cli_writeln!(io, "{}", receipt),
+                    Err(err) => cli_writeln!(io, "{}\nAssets were not transferred.", err),
+                }
+            }
+        ),
+    ]
+}
+
 #[async_trait]
 impl<'a> CLI<'a> for EspressoCli {
     type Ledger = EspressoLedger;
@@ -115,13 +274,32 @@ impl<'a> CLI<'a> for EspressoCli {
         univ_param: &'a UniversalParam,
         args: Self::Args,
     ) -> Result<Self::Backend, KeystoreError<EspressoLedger>> {
-        NetworkBackend::new(
+        let contacts_path = match args.contacts.clone() {
+            Some(path) => path,
+            None => {
+                let home = std::env::var("HOME").map_err(|_| KeystoreError::Failed {
+                    msg: "HOME directory is not set; use --contacts to choose a contact book"
+                        .into(),
+                })?;
+                [&home, ".translucence", "contacts"].iter().collect()
+            }
+        };
+        let contacts = ContactBook::load(contacts_path)?;
+
+        let backend = NetworkBackend::from_config(
             univ_param,
-            args.esqs_url,
-            args.address_book_url,
-            args.submit_url,
+            NetworkConfig {
+                esqs_url: args.esqs_url,
+                esqs_fallback_urls: args.esqs_fallback_url,
+                address_book_url: args.address_book_url,
+                validator_url: args.submit_url,
+                prover_key_dir: args.prover_key_dir,
+                prover_key_url: args.prover_key_url,
+                ..Default::default()
+            },
         )
-        .await
+        .await?;
+        Ok(backend.with_contact_book(contacts))
     }
 
     async fn init_loader(
@@ -130,6 +308,10 @@ impl<'a> CLI<'a> for EspressoCli {
     ) -> Result<Self::Loader, KeystoreError<Self::Ledger>> {
         Ok(InteractiveLoader::new(storage, input))
     }
+
+    fn extra_commands() -> Vec<Command<'a, Self>> {
+        contact_commands()
+    }
 }
 
 #[async_std::main]
@@ -0,0 +1,244 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Operational metrics for keystores.
+//!
+//! A [ClientMetrics] registry counts the transactions a
+//! [NetworkBackend](crate::network::NetworkBackend) submits and their outcomes, records the latency
+//! of requests to the EsQS and validator, and tracks how far the keystore has synced. Services
+//! running many keystores can share one registry between all of their backends, or give each its
+//! own, and expose the registry in the Prometheus text format using [ClientMetrics::render].
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the buckets used for latency histograms.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// A histogram of durations, with cumulative buckets as in Prometheus.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The sum of all observations.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count()).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum().as_secs_f64()).unwrap();
+        writeln!(out, "{}_count {}", name, self.count()).unwrap();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClientMetrics {
+    submitted: AtomicU64,
+    submit_failures: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    queued: AtomicU64,
+    // Block heights (numbers of blocks) of the ledger and of the keystore's view of it.
+    latest_height: AtomicU64,
+    synced_height: AtomicU64,
+    /// The latency of requests to the EsQS.
+    pub query_latency: Histogram,
+    /// The latency of transaction submissions to the validator.
+    pub submit_latency: Histogram,
+    /// The time taken to load or generate proving keys.
+    pub prover_key_load_time: Histogram,
+}
+
+impl ClientMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record_submission(&self, elapsed: Duration, succeeded: bool) {
+        self.submit_latency.observe(elapsed);
+        if succeeded {
+            self.submitted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.submit_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_outcome(&self, accepted: bool) {
+        if accepted {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set_queued(&self, queued: usize) {
+        self.queued.store(queued as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_latest_height(&self, height: u64) {
+        self.latest_height.fetch_max(height, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_synced_height(&self, height: u64) {
+        self.synced_height.fetch_max(height, Ordering::Relaxed);
+        self.set_latest_height(height);
+    }
+
+    /// The number of transactions successfully submitted to the validator.
+    pub fn submitted(&self) -> u64 {
+        self.submitted.load(Ordering::Relaxed)
+    }
+
+    /// The number of transactions which the validator refused to accept for submission.
+    pub fn submit_failures(&self) -> u64 {
+        self.submit_failures.load(Ordering::Relaxed)
+    }
+
+    /// The number of submitted transactions which were included in a block.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// The number of submitted transactions which were rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// The number of transactions waiting for their turn to be submitted.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// The number of blocks the keystore has yet to receive from the EsQS.
+    ///
+    /// This is measured against the latest block height reported by the EsQS when the keystore
+    /// subscribed to events, or the latest block received since then, whichever is greater.
+    pub fn sync_lag(&self) -> u64 {
+        self.latest_height
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.synced_height.load(Ordering::Relaxed))
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "espresso_client_transactions_submitted_total",
+                "Transactions submitted to the validator.",
+                self.submitted(),
+            ),
+            (
+                "espresso_client_submit_failures_total",
+                "Transactions the validator refused to accept.",
+                self.submit_failures(),
+            ),
+            (
+                "espresso_client_transactions_accepted_total",
+                "Submitted transactions included in a block.",
+                self.accepted(),
+            ),
+            (
+                "espresso_client_transactions_rejected_total",
+                "Submitted transactions rejected by the validators.",
+                self.rejected(),
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        let gauges = [
+            (
+                "espresso_client_submit_queue_depth",
+                "Transactions waiting to be submitted.",
+                self.queued(),
+            ),
+            (
+                "espresso_client_sync_lag_blocks",
+                "Blocks not yet received from the EsQS.",
+                self.sync_lag(),
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} gauge", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        self.query_latency.render(
+            &mut out,
+            "espresso_client_query_latency_seconds",
+            "Latency of EsQS requests.",
+        );
+        self.submit_latency.render(
+            &mut out,
+            "espresso_client_submit_latency_seconds",
+            "Latency of transaction submissions.",
+        );
+        self.prover_key_load_time.render(
+            &mut out,
+            "espresso_client_prover_key_load_seconds",
+            "Time taken to load proving keys.",
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let metrics = ClientMetrics::new();
+        metrics.submit_latency.observe(Duration::from_millis(20));
+        metrics.submit_latency.observe(Duration::from_secs(2));
+        metrics.record_submission(Duration::from_millis(1), true);
+
+        assert_eq!(metrics.submitted(), 1);
+        assert_eq!(metrics.submit_latency.count(), 3);
+        let rendered = metrics.render();
+        assert!(rendered.contains("espresso_client_submit_latency_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(
+            rendered.contains("espresso_client_submit_latency_seconds_bucket{le=\"0.025\"} 2\n")
+        );
+        assert!(rendered.contains("espresso_client_submit_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("espresso_client_transactions_submitted_total 1\n"));
+    }
+}
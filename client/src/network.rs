@@ -1,44 +1,123 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+use crate::connection::{Backoff, ConnectionStatus, QueryConnection, SyncMode, SyncStatus};
+use crate::contacts::ContactBook;
+use crate::hooks::BackendHook;
+use crate::metrics::ClientMetrics;
+use crate::prover_keys::ProverKeyStore;
+use crate::redact::sensitive;
+use crate::submit_queue::{SubmitLimits, SubmitQueue};
+use crate::tracking::TransactionTracker;
 use address_book::{error::AddressBookError, InsertPubKey};
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Mutex, MutexGuard};
 use async_trait::async_trait;
-use espresso_availability_api::query_data::StateQueryData;
+use espresso_availability_api::query_data::{StateQueryData, TransactionQueryData};
 use espresso_core::{
     ledger::EspressoLedger,
     set_merkle_tree::{SetMerkleProof, SetMerkleTree},
-    state::ElaboratedTransaction,
-    universal_params::MERKLE_HEIGHT,
+    state::{ElaboratedTransaction, TransactionCommitment},
 };
 use espresso_esqs::ApiError;
 use espresso_metastate_api::api::NullifierCheck;
-use futures::future::ready;
 use futures::prelude::*;
 use jf_cap::keys::{UserAddress, UserKeyPair, UserPubKey};
-use jf_cap::proof::{freeze::FreezeProvingKey, transfer::TransferProvingKey, UniversalParam};
+use jf_cap::proof::UniversalParam;
 use jf_cap::structs::Nullifier;
 use jf_cap::MerkleTree;
-use key_set::{ProverKeySet, SizedKey};
 use reef::Ledger;
 use seahorse::transactions::Transaction;
 use seahorse::{
     events::{EventIndex, EventSource, LedgerEvent},
     ledger_state::LedgerState,
     lw_merkle_tree::LWMerkleTree,
-    CryptoSnafu, KeystoreBackend, KeystoreError,
+    KeystoreBackend, KeystoreError,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use snafu::ResultExt;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use surf_disco::{Client, Url};
+use tracing::{debug, info, instrument, warn};
+
+/// How long a public key fetched from the address book is used before it is fetched again.
+pub const DEFAULT_PUB_KEY_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a submitted transaction is remembered, so that submitting it again does not send it
+/// again if it already reached the validator.
+///
+/// Submissions are only remembered in memory, by the backend which made them. A transaction
+/// submitted again after the process restarts, or through a different backend, is sent again. The
+/// keystore's own record of pending transactions is persisted, but it does not resubmit them.
+pub const DUPLICATE_SUBMISSION_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Configuration for a [NetworkBackend].
+///
+/// The [Default] configuration connects to services running locally on their default ports, and
+/// uses the same settings as a backend created with [NetworkBackend::new].
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub esqs_url: Url,
+    /// Other EsQS instances to fail over to, in order of preference.
+    pub esqs_fallback_urls: Vec<Url>,
+    pub address_book_url: Url,
+    /// The validator to submit transactions to.
+    pub validator_url: Url,
+    /// How long to wait for the EsQS to become available when creating the backend.
+    pub connect_timeout: Duration,
+    /// See [NetworkBackend::with_reconnect_backoff].
+    pub reconnect_backoff: Backoff,
+    /// See [NetworkBackend::with_sync_mode].
+    pub sync_mode: SyncMode,
+    /// See [NetworkBackend::with_pub_key_ttl].
+    pub pub_key_ttl: Duration,
+    /// See [NetworkBackend::with_submit_limits]. If [None], submissions are not rate limited.
+    pub submit_limits: Option<SubmitLimits>,
+    /// Directory in which to cache proving keys. See [ProverKeyStore::with_cache_dir].
+    pub prover_key_dir: Option<PathBuf>,
+    /// URL from which to download proving keys. See [ProverKeyStore::with_download_url].
+    pub prover_key_url: Option<Url>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            esqs_url: "http://localhost:50087".parse().unwrap(),
+            esqs_fallback_urls: vec![],
+            address_book_url: "http://localhost:50088".parse().unwrap(),
+            validator_url: "http://localhost:50089".parse().unwrap(),
+            connect_timeout: Duration::from_secs(300),
+            reconnect_backoff: Backoff::default(),
+            sync_mode: SyncMode::default(),
+            pub_key_ttl: DEFAULT_PUB_KEY_TTL,
+            submit_limits: None,
+            prover_key_dir: None,
+            prover_key_url: None,
+        }
+    }
+}
 
 pub struct NetworkBackend<'a> {
-    univ_param: &'a UniversalParam,
-    query_client: Client<ApiError>,
+    prover_keys: ProverKeyStore<'a>,
+    queries: Arc<QueryConnection>,
     address_book_client: Client<AddressBookError>,
     validator_client: Client<ApiError>,
+    // Public keys recently fetched from the address book, with the time they were fetched.
+    pub_keys: Mutex<HashMap<UserAddress, (UserPubKey, Instant)>>,
+    pub_key_ttl: Duration,
+    submit_queue: Option<Arc<SubmitQueue>>,
+    // Transactions recently submitted to the validator, including submissions which failed.
+    recent_submissions: HashMap<TransactionCommitment, RecentSubmission>,
+    metrics: Option<Arc<ClientMetrics>>,
+    hooks: Arc<Vec<Arc<dyn BackendHook>>>,
+    contacts: Option<Mutex<ContactBook>>,
+}
+
+struct RecentSubmission {
+    submitted: Instant,
+    // Whether the validator accepted the submission. If not, it may still have received it.
+    accepted: bool,
 }
 
 impl<'a> NetworkBackend<'a> {
@@ -48,27 +127,196 @@ impl<'a> NetworkBackend<'a> {
         address_book_url: Url,
         validator_url: Url,
     ) -> Result<NetworkBackend<'a>, KeystoreError<EspressoLedger>> {
-        let backend = Self {
-            query_client: Self::client(query_url),
-            address_book_client: Self::client(address_book_url),
-            validator_client: Self::client(validator_url),
+        Self::from_config(
             univ_param,
+            NetworkConfig {
+                esqs_url: query_url,
+                address_book_url,
+                validator_url,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Create a backend from `config`, waiting until one of its EsQS endpoints is available.
+    pub async fn from_config(
+        univ_param: &'a UniversalParam,
+        config: NetworkConfig,
+    ) -> Result<NetworkBackend<'a>, KeystoreError<EspressoLedger>> {
+        let mut prover_keys = ProverKeyStore::new(univ_param);
+        if let Some(dir) = config.prover_key_dir {
+            prover_keys = prover_keys.with_cache_dir(dir);
+        }
+        if let Some(url) = config.prover_key_url {
+            prover_keys = prover_keys.with_download_url(url);
+        }
+        let mut query_urls = vec![config.esqs_url];
+        query_urls.extend(config.esqs_fallback_urls);
+
+        let backend = Self {
+            queries: Arc::new(
+                QueryConnection::new(query_urls, config.reconnect_backoff)?
+                    .with_sync_mode(config.sync_mode),
+            ),
+            address_book_client: Self::client(config.address_book_url),
+            validator_client: Self::client(config.validator_url),
+            prover_keys,
+            pub_keys: Default::default(),
+            pub_key_ttl: config.pub_key_ttl,
+            submit_queue: config
+                .submit_limits
+                .map(|limits| Arc::new(SubmitQueue::new(limits))),
+            recent_submissions: Default::default(),
+            metrics: None,
+            hooks: Default::default(),
+            contacts: None,
         };
-        backend.wait_for_esqs().await?;
+        backend.wait_for_esqs(config.connect_timeout).await?;
         Ok(backend)
     }
 
+    /// Use `prover_keys` to load proving keys when creating a new keystore.
+    ///
+    /// By default, proving keys are preprocessed from the universal parameters every time.
+    pub fn with_prover_key_store(mut self, prover_keys: ProverKeyStore<'a>) -> Self {
+        self.prover_keys = prover_keys;
+        self
+    }
+
+    /// Reuse public keys fetched from the address book for `ttl` before fetching them again.
+    ///
+    /// The address book allows the public key for an address to be replaced, so cached keys can
+    /// become stale. A `ttl` of zero disables caching.
+    pub fn with_pub_key_ttl(mut self, ttl: Duration) -> Self {
+        self.pub_key_ttl = ttl;
+        self
+    }
+
+    /// Limit the rate at which transactions are submitted to the validator.
+    ///
+    /// By default, every transaction is submitted as soon as the keystore produces it.
+    pub fn with_submit_limits(mut self, limits: SubmitLimits) -> Self {
+        self.with_submit_queue(Arc::new(SubmitQueue::new(limits)))
+    }
+
+    /// Submit transactions through `queue`.
+    ///
+    /// A backend only submits one transaction at a time, so to limit the combined submissions of
+    /// several keystores, such as the workers of a faucet, create their backends with the same
+    /// queue.
+    pub fn with_submit_queue(mut self, queue: Arc<SubmitQueue>) -> Self {
+        self.submit_queue = Some(queue);
+        self
+    }
+
+    /// Record operational metrics in `metrics`.
+    ///
+    /// The same registry may be shared by many backends, in which case it aggregates their
+    /// metrics.
+    pub fn with_metrics(mut self, metrics: Arc<ClientMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Fail over to `fallbacks`, in order, when the primary EsQS is unavailable.
+    ///
+    /// Requests which fail are retried against the next EsQS, and event subscriptions which drop
+    /// are re-established on the next EsQS without losing events. All of the endpoints must serve
+    /// the same ledger.
+    pub fn with_query_fallbacks(mut self, fallbacks: impl IntoIterator<Item = Url>) -> Self {
+        Arc::make_mut(&mut self.queries).add_fallbacks(fallbacks);
+        self
+    }
+
+    /// Wait according to `backoff` between attempts to re-establish a dropped event subscription.
+    pub fn with_reconnect_backoff(mut self, backoff: Backoff) -> Self {
+        Arc::make_mut(&mut self.queries).set_backoff(backoff);
+        self
+    }
+
+    /// Receive ledger events according to `mode`.
+    ///
+    /// By default, events are pushed by the EsQS over a websocket.
+    pub fn with_sync_mode(mut self, mode: SyncMode) -> Self {
+        Arc::make_mut(&mut self.queries).set_sync_mode(mode);
+        self
+    }
+
+    /// How far the keystore using this backend has synced with the ledger.
+    ///
+    /// The keystore's height is the height of the latest block delivered to it by an event
+    /// subscription, which the keystore may still be processing.
+    pub async fn sync_status(&self) -> Result<SyncStatus, KeystoreError<EspressoLedger>> {
+        self.queries.sync_status().await
+    }
+
+    /// The state of the connection to the EsQS.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.queries.status()
+    }
+
+    /// A [TransactionTracker] which queries the EsQS through this backend's connection.
+    pub fn transaction_tracker(&self) -> TransactionTracker {
+        TransactionTracker::new(self.queries.clone())
+    }
+
+    /// Call `hook` around submissions and for every ledger event.
+    ///
+    /// Hooks are called in the order they are added.
+    pub fn with_hook(mut self, hook: Arc<dyn BackendHook>) -> Self {
+        Arc::make_mut(&mut self.hooks).push(hook);
+        self
+    }
+
+    /// Keep `contacts` with this backend.
+    ///
+    /// Code which only has access to a keystore, such as the commands of the keystore CLI, can then
+    /// reach the contact book through the keystore's backend.
+    pub fn with_contact_book(mut self, contacts: ContactBook) -> Self {
+        self.contacts = Some(Mutex::new(contacts));
+        self
+    }
+
+    /// The contact book given to [with_contact_book](Self::with_contact_book), if any.
+    pub async fn contact_book(&self) -> Option<MutexGuard<'_, ContactBook>> {
+        match &self.contacts {
+            Some(contacts) => Some(contacts.lock().await),
+            None => None,
+        }
+    }
+
+    /// The metrics registry used by this backend, if any.
+    pub fn metrics(&self) -> Option<&Arc<ClientMetrics>> {
+        self.metrics.as_ref()
+    }
+
+    /// The number of transactions waiting for their turn to be submitted.
+    ///
+    /// If the submit queue is shared, this includes transactions from other backends.
+    pub fn queued_submissions(&self) -> usize {
+        self.submit_queue
+            .as_ref()
+            .map(|queue| queue.queue_len())
+            .unwrap_or(0)
+    }
+
     async fn get<T: DeserializeOwned>(
         &self,
         uri: impl AsRef<str>,
     ) -> Result<T, KeystoreError<EspressoLedger>> {
-        self.query_client
-            .get(uri.as_ref())
-            .send()
-            .await
-            .map_err(|source| KeystoreError::Failed {
-                msg: format!("EsQS request GET {} failed: {}", uri.as_ref(), source),
-            })
+        let start = Instant::now();
+        let res = self.queries.get(uri.as_ref()).await;
+        if let Some(metrics) = &self.metrics {
+            metrics.query_latency.observe(start.elapsed());
+        }
+        debug!(
+            uri = %sensitive(&uri.as_ref()),
+            elapsed = ?start.elapsed(),
+            ok = res.is_ok(),
+            "EsQS GET"
+        );
+        res
     }
 
     async fn post<T: Serialize, E: surf_disco::Error>(
@@ -76,22 +324,28 @@ impl<'a> NetworkBackend<'a> {
         uri: impl AsRef<str>,
         body: &T,
     ) -> Result<(), KeystoreError<EspressoLedger>> {
-        client
+        let start = Instant::now();
+        let res = client
             .post(uri.as_ref())
             .body_binary(body)
             .map_err(|source| KeystoreError::Failed {
                 msg: format!("failed to build request POST {}: {}", uri.as_ref(), source),
             })?
             .send()
-            .await
-            .map_err(|source| KeystoreError::Failed {
-                msg: format!("request POST {} failed: {}", uri.as_ref(), source),
-            })
+            .await;
+        debug!(
+            uri = %sensitive(&uri.as_ref()),
+            elapsed = ?start.elapsed(),
+            ok = res.is_ok(),
+            "POST"
+        );
+        res.map_err(|source| KeystoreError::Failed {
+            msg: format!("request POST {} failed: {}", uri.as_ref(), source),
+        })
     }
 
-    async fn wait_for_esqs(&self) -> Result<(), KeystoreError<EspressoLedger>> {
-        let timeout = Duration::from_secs(300);
-        if self.query_client.connect(Some(timeout)).await {
+    async fn wait_for_esqs(&self, timeout: Duration) -> Result<(), KeystoreError<EspressoLedger>> {
+        if self.queries.connect(timeout).await {
             Ok(())
         } else {
             let msg = format!("failed to connect to EQS after {:?}", timeout);
@@ -100,6 +354,19 @@ impl<'a> NetworkBackend<'a> {
         }
     }
 
+    /// Whether the transaction with `hash` has been included in a block.
+    ///
+    /// Errors are treated as the transaction not being found.
+    async fn on_ledger(&self, hash: &TransactionCommitment) -> bool {
+        let uri = format!("availability/gettransaction/hash/{}", hash);
+        matches!(
+            self.queries
+                .get_optional::<TransactionQueryData>(&uri)
+                .await,
+            Ok(Some(_))
+        )
+    }
+
     fn client<E: surf_disco::Error>(url: Url) -> Client<E> {
         Client::builder(url)
             .set_timeout(Some(Duration::from_secs(5 * 60)))
@@ -112,58 +379,31 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
     type EventStream =
         Pin<Box<dyn Send + Unpin + Stream<Item = (LedgerEvent<EspressoLedger>, EventSource)>>>;
 
+    #[instrument(skip(self))]
     async fn create(
         &mut self,
     ) -> Result<LedgerState<'a, EspressoLedger>, KeystoreError<EspressoLedger>> {
         let block_id: u64 = self.get("status/latest_block_id").await?;
+        info!(block_id, "creating ledger state from EsQS snapshot");
+        self.queries.set_synced_height(block_id + 1);
+        if let Some(metrics) = &self.metrics {
+            metrics.set_synced_height(block_id + 1);
+        }
         let snapshot: StateQueryData = self
             .get(format!("availability/getstate/{}", block_id))
             .await?;
 
         // Construct proving keys of the same arities as the verifier keys from the validator.
-        let univ_param = self.univ_param;
-        let proving_keys = Arc::new(ProverKeySet {
-            mint: jf_cap::proof::mint::preprocess(univ_param, MERKLE_HEIGHT)
-                .context(CryptoSnafu)?
-                .0,
-            freeze: snapshot
-                .state
-                .chain
-                .verif_crs
-                .freeze
-                .iter()
-                .map(|k| {
-                    Ok::<FreezeProvingKey, KeystoreError<EspressoLedger>>(
-                        jf_cap::proof::freeze::preprocess(
-                            univ_param,
-                            k.num_inputs(),
-                            MERKLE_HEIGHT,
-                        )
-                        .context(CryptoSnafu)?
-                        .0,
-                    )
-                })
-                .collect::<Result<_, _>>()?,
-            xfr: snapshot
-                .state
-                .chain
-                .verif_crs
-                .xfr
-                .iter()
-                .map(|k| {
-                    Ok::<TransferProvingKey, KeystoreError<EspressoLedger>>(
-                        jf_cap::proof::transfer::preprocess(
-                            univ_param,
-                            k.num_inputs(),
-                            k.num_outputs(),
-                            MERKLE_HEIGHT,
-                        )
-                        .context(CryptoSnafu)?
-                        .0,
-                    )
-                })
-                .collect::<Result<_, _>>()?,
-        });
+        let start = Instant::now();
+        let proving_keys = Arc::new(
+            self.prover_keys
+                .load(&snapshot.state.chain.verif_crs)
+                .await?,
+        );
+        info!(elapsed = ?start.elapsed(), "loaded proving keys");
+        if let Some(metrics) = &self.metrics {
+            metrics.prover_key_load_time.observe(start.elapsed());
+        }
 
         let state = LedgerState::new(
             proving_keys,
@@ -189,49 +429,52 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
         // All events come from a single source, the EsQS, which aggregates blocks and memos.
         let from = from.index(EventSource::QueryService);
         let to = to.map(|to| to.index(EventSource::QueryService));
+        info!(from, ?to, "subscribing to ledger events");
 
-        //todo !jeb.bearer handle connection failures.
-        //      https://github.com/EspressoSystems/seahorse/issues/117
-        // This should only fail if the server is incorrect or down, so we should handle by retrying
-        // or failing over to a different server.
-        let all_events = self
-            .query_client
-            .socket(&format!("catchup/subscribe_for_events/{}", from))
-            .subscribe()
-            .await
-            .expect("failed to connect to server");
-        let chosen_events: Pin<Box<dyn Stream<Item = _> + Send>> = if let Some(to) = to {
-            Box::pin(all_events.take(to - from))
-        } else {
-            Box::pin(all_events)
-        };
-
-        Box::pin(
-            chosen_events
-                //todo !jeb.bearer handle stream errors
-                //      https://github.com/EspressoSystems/seahorse/issues/117
-                // If there is an error in the stream, or the server sends us invalid data, we
-                // should retry or fail over to a different server.
-                .filter_map(|msg| ready(msg.ok().map(|e| (e, EventSource::QueryService)))),
-        )
+        let metrics = self.metrics.clone();
+        if let Some(metrics) = &metrics {
+            // Learn how far behind we are, so that the sync lag is meaningful while catching up.
+            match self.get::<u64>("status/latest_block_id").await {
+                Ok(block_id) => metrics.set_latest_height(block_id + 1),
+                Err(err) => warn!("failed to get latest block height: {}", sensitive(&err)),
+            }
+        }
+        // Connection failures and stream errors are handled by reconnecting, possibly to a
+        // different EsQS, and resuming from the first event we have not yet seen.
+        let hooks = self.hooks.clone();
+        Box::pin(self.queries.clone().subscribe(from, to).then(move |event| {
+            debug!("received ledger event {}", event_summary(&event));
+            if let (Some(metrics), LedgerEvent::Commit { block_id, .. }) = (&metrics, &event) {
+                metrics.set_synced_height(*block_id + 1);
+            }
+            let hooks = hooks.clone();
+            // Box the future, so that the stream is Unpin.
+            Box::pin(async move {
+                for hook in hooks.iter() {
+                    hook.on_event(&event).await;
+                }
+                (event, EventSource::QueryService)
+            })
+        }))
     }
 
     async fn get_public_key(
         &self,
         address: &UserAddress,
     ) -> Result<UserPubKey, KeystoreError<EspressoLedger>> {
-        self.address_book_client
-            .post("request_pubkey")
-            .body_json(address)
-            .unwrap()
-            .send()
+        if let Some((pub_key, fetched)) = self.pub_keys.lock().await.get(address) {
+            if fetched.elapsed() < self.pub_key_ttl {
+                debug!(address = %sensitive(address), "using cached public key");
+                return Ok(pub_key.clone());
+            }
+        }
+
+        let pub_key = request_public_key(&self.address_book_client, address).await?;
+        self.pub_keys
+            .lock()
             .await
-            .map_err(|source| KeystoreError::Failed {
-                msg: format!(
-                    "Address book request POST /request_pubkey failed: {}",
-                    source
-                ),
-            })
+            .insert(address.clone(), (pub_key.clone(), Instant::now()));
+        Ok(pub_key)
     }
 
     async fn get_nullifier_proof(
@@ -246,8 +489,16 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
             let (spent, proof) = if block_height == 0 {
                 // The nullifier set at block height 0 (i.e. before the genesis block) is always the
                 // default, empty set.
-                assert_eq!(*set, SetMerkleTree::default());
-                set.contains(nullifier).unwrap()
+                if set.hash() != SetMerkleTree::default().hash() {
+                    return Err(KeystoreError::Failed {
+                        msg: "nullifier set before the genesis block is not empty".into(),
+                    });
+                }
+                SetMerkleTree::default()
+                    .contains(nullifier)
+                    .ok_or_else(|| KeystoreError::Failed {
+                        msg: "empty nullifier set has no proof of non-membership".into(),
+                    })?
             } else {
                 let NullifierCheck { proof, spent } = self
                     .get(format!(
@@ -258,8 +509,7 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
                     .await?;
                 (spent, proof)
             };
-            set.remember(nullifier, proof.clone()).unwrap();
-            Ok((spent, proof))
+            remember_nullifier_proof(set, nullifier, spent, proof)
         }
     }
 
@@ -278,7 +528,12 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
             .await
             .map_err(|err| KeystoreError::Failed {
                 msg: format!("error inserting public key: {}", err),
-            })
+            })?;
+        self.pub_keys
+            .lock()
+            .await
+            .insert(key_pair.address(), (key_pair.pub_key(), Instant::now()));
+        Ok(())
     }
 
     async fn submit(
@@ -293,11 +548,111 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
             ));
         }
 
-        Self::post(&self.validator_client, "/validator/submit", &txn).await
+        let hash = TransactionCommitment(txn.txn.hash());
+        // A transaction is identified by its hash, so an application retrying a submission sends a
+        // transaction with the same hash. If an earlier copy reached the validator, don't send it
+        // again: the second copy would be rejected as a double spend, and the receipt the caller
+        // already has identifies the original. A submission which failed, for example with a
+        // network error, may still have reached the validator, so before retrying it, check whether
+        // it is already on the ledger. This only covers submissions made by this backend since it
+        // was created (see DUPLICATE_SUBMISSION_WINDOW).
+        self.recent_submissions
+            .retain(|_, submission| submission.submitted.elapsed() < DUPLICATE_SUBMISSION_WINDOW);
+        let retrying = match self.recent_submissions.get(&hash) {
+            Some(submission) if submission.accepted => {
+                info!(
+                    hash = %sensitive(&hash),
+                    "transaction was already submitted, not submitting it again"
+                );
+                return Ok(());
+            }
+            Some(_) => {
+                if self.on_ledger(&hash).await {
+                    info!(
+                        hash = %sensitive(&hash),
+                        "failed submission reached the ledger, not submitting it again"
+                    );
+                    return Ok(());
+                }
+                true
+            }
+            None => false,
+        };
+        for hook in self.hooks.iter() {
+            if let Err(err) = hook.before_submit(&txn, &txn_info).await {
+                info!(
+                    hash = %sensitive(&hash),
+                    "transaction submission vetoed by hook: {}",
+                    sensitive(&err)
+                );
+                return Err(err);
+            }
+        }
+
+        let _permit = match &self.submit_queue {
+            Some(queue) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_queued(queue.queue_len() + 1);
+                }
+                let permit = queue.acquire().await;
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_queued(queue.queue_len());
+                }
+                Some(permit)
+            }
+            None => None,
+        };
+        info!(hash = %sensitive(&hash), retrying, "submitting transaction");
+        // Remember the attempt before making it, so that a retry can tell that this transaction may
+        // have reached the validator even if we never get a response.
+        self.recent_submissions.insert(
+            hash,
+            RecentSubmission {
+                submitted: Instant::now(),
+                accepted: false,
+            },
+        );
+        let start = Instant::now();
+        let res = Self::post(&self.validator_client, "/validator/submit", &txn).await;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_submission(start.elapsed(), res.is_ok());
+        }
+        match res {
+            Ok(()) => {
+                if let Some(submission) = self.recent_submissions.get_mut(&hash) {
+                    submission.accepted = true;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                // The validator rejects a copy of a transaction which is already on the ledger.
+                if retrying && self.on_ledger(&hash).await {
+                    info!(hash = %sensitive(&hash), "transaction was already on the ledger");
+                    return Ok(());
+                }
+                warn!(
+                    hash = %sensitive(&hash),
+                    "failed to submit transaction: {}",
+                    sensitive(&err)
+                );
+                Err(err)
+            }
+        }
     }
 
-    async fn finalize(&mut self, _txn: Transaction<EspressoLedger>, _txid: Option<(u64, u64)>) {
+    async fn finalize(&mut self, txn: Transaction<EspressoLedger>, txid: Option<(u64, u64)>) {
         // -> Result<(), KeystoreError<EspressoLedger>>
+        // The keystore finalizes a transaction with its location once it is included in a block,
+        // or without a location if it is rejected.
+        if let Some(metrics) = &self.metrics {
+            metrics.record_outcome(txid.is_some());
+        }
+        for hook in self.hooks.iter() {
+            match txid {
+                Some((block_id, txn_id)) => hook.after_accept(&txn, block_id, txn_id).await,
+                None => hook.after_reject(&txn).await,
+            }
+        }
     }
 
     async fn get_initial_scan_state(
@@ -310,3 +665,215 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
         ))
     }
 }
+
+async fn request_public_key(
+    client: &Client<AddressBookError>,
+    address: &UserAddress,
+) -> Result<UserPubKey, KeystoreError<EspressoLedger>> {
+    debug!(address = %sensitive(address), "fetching public key");
+    client
+        .post("request_pubkey")
+        .body_json(address)
+        .unwrap()
+        .send()
+        .await
+        .map_err(|source| KeystoreError::Failed {
+            msg: format!(
+                "Address book request POST /request_pubkey failed: {}",
+                source
+            ),
+        })
+}
+
+/// A short description of a ledger event, suitable for logging.
+/// Check a proof that `nullifier` is or is not `spent`, and remember it in `set`.
+///
+/// Don't trust the EsQS: a proof which does not match the root of `set`, or which contradicts the
+/// claimed `spent` flag, is an error, not a panic, and leaves `set` unchanged.
+fn remember_nullifier_proof(
+    set: &mut SetMerkleTree,
+    nullifier: Nullifier,
+    spent: bool,
+    proof: SetMerkleProof,
+) -> Result<(bool, SetMerkleProof), KeystoreError<EspressoLedger>> {
+    match proof.check(nullifier, &set.hash()) {
+        Ok(proven) if proven == spent => {}
+        _ => {
+            return Err(KeystoreError::Failed {
+                msg: format!("EsQS returned an invalid proof for nullifier {}", nullifier),
+            })
+        }
+    }
+    set.remember(nullifier, proof.clone())
+        .map_err(|_| KeystoreError::Failed {
+            msg: format!("failed to remember nullifier proof for {}", nullifier),
+        })?;
+    Ok((spent, proof))
+}
+
+fn event_summary(event: &LedgerEvent<EspressoLedger>) -> String {
+    match event {
+        LedgerEvent::Commit {
+            block, block_id, ..
+        } => {
+            format!("Commit(block {}, {} transactions)", block_id, block.len())
+        }
+        LedgerEvent::Reject { block, .. } => {
+            format!("Reject({} transactions)", block.len())
+        }
+        LedgerEvent::Memos { outputs, .. } => format!("Memos({} outputs)", outputs.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_remember_nullifier_proof() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let spent = Nullifier::random_for_test(&mut rng);
+        let unspent = Nullifier::random_for_test(&mut rng);
+        let mut full = SetMerkleTree::default();
+        full.insert(spent).unwrap();
+        let mut other = SetMerkleTree::default();
+        other.insert(unspent).unwrap();
+
+        let (_, spent_proof) = full.contains(spent).unwrap();
+        let (_, unspent_proof) = full.contains(unspent).unwrap();
+        let (_, wrong_root_proof) = other.contains(spent).unwrap();
+        let mut set = SetMerkleTree::sparse(full.hash());
+        for (nullifier, claim, proof) in [
+            // A proof against a different nullifier set.
+            (spent, false, wrong_root_proof),
+            // A proof for a different nullifier.
+            (spent, false, unspent_proof.clone()),
+            // A proof which contradicts the claimed spent flag.
+            (spent, false, spent_proof.clone()),
+            (unspent, true, unspent_proof.clone()),
+        ] {
+            assert!(remember_nullifier_proof(&mut set, nullifier, claim, proof).is_err());
+            // Rejected proofs are not cached.
+            assert_eq!(set.contains(nullifier), None);
+        }
+        assert_eq!(set.hash(), full.hash());
+
+        // Valid proofs are returned and cached.
+        assert!(
+            remember_nullifier_proof(&mut set, spent, true, spent_proof)
+                .unwrap()
+                .0
+        );
+        assert!(
+            !remember_nullifier_proof(&mut set, unspent, false, unspent_proof)
+                .unwrap()
+                .0
+        );
+        assert!(set.contains(spent).unwrap().0);
+        assert!(!set.contains(unspent).unwrap().0);
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod slow_tests {
+    use super::*;
+    use crate::testing::network::{retry, FundedNetwork};
+    use espresso_core::universal_params::UNIVERSAL_PARAM;
+    use jf_cap::structs::AssetCode;
+    use primitive_types::U256;
+    use seahorse::{ledger_state::TransactionStatus, RecordAmount};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A hook which keeps a copy of each transaction the backend is about to send.
+    #[derive(Default)]
+    struct Capture {
+        submitted: std::sync::Mutex<Vec<(ElaboratedTransaction, Transaction<EspressoLedger>)>>,
+        sent: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BackendHook for Capture {
+        async fn before_submit(
+            &self,
+            txn: &ElaboratedTransaction,
+            info: &Transaction<EspressoLedger>,
+        ) -> Result<(), KeystoreError<EspressoLedger>> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            self.submitted
+                .lock()
+                .unwrap()
+                .push((txn.clone(), info.clone()));
+            Ok(())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_retry_after_ambiguous_failure() {
+        let mut network = FundedNetwork::new(6).await;
+
+        // Capture a transaction as it is submitted by a keystore.
+        let capture = Arc::new(Capture::default());
+        let backend = network.backend().await.with_hook(capture.clone());
+        let mut payer = network.new_keystore_with_backend(backend).await;
+        let payer_key = payer
+            .generate_sending_account("payer".into(), None)
+            .await
+            .unwrap();
+        let grant = network
+            .keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(payer_key.clone(), RecordAmount::from(1000u64))],
+                RecordAmount::from(1u64),
+            )
+            .await
+            .unwrap();
+        network.keystore.await_transaction(&grant).await.unwrap();
+        retry(|| async {
+            payer
+                .balance_breakdown(&payer_key.address(), &AssetCode::native())
+                .await
+                == U256::from(1000u64)
+        })
+        .await;
+        let receipt = payer
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(payer_key.clone(), RecordAmount::from(100u64))],
+                RecordAmount::from(1u64),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            payer.await_transaction(&receipt).await.unwrap(),
+            TransactionStatus::Retired
+        ));
+        let (txn, info) = capture.submitted.lock().unwrap()[0].clone();
+
+        // A backend whose validator cannot be reached fails to submit the transaction, although
+        // (as far as it can tell) the transaction may have reached the validator.
+        let resubmissions = Arc::new(Capture::default());
+        let mut backend = NetworkBackend::from_config(
+            &UNIVERSAL_PARAM,
+            NetworkConfig {
+                esqs_url: network.network.query_api.clone(),
+                address_book_url: network.network.address_book_api.clone(),
+                validator_url: "http://127.0.0.1:1".parse().unwrap(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+        .with_hook(resubmissions.clone());
+        assert!(backend.submit(txn.clone(), info.clone()).await.is_err());
+        assert_eq!(resubmissions.sent.load(Ordering::SeqCst), 1);
+
+        // Retrying within the window finds the transaction on the ledger, and does not send it
+        // again.
+        backend.submit(txn, info).await.unwrap();
+        assert_eq!(resubmissions.sent.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Small local files holding the state of client features, such as contacts and schedules.
+//!
+//! Each file holds one bincode-serialized value, and is replaced as a whole whenever the value
+//! changes.
+
+use crate::EspressoKeystoreError;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Load the value stored at `path`, or the default value if `path` does not exist.
+///
+/// `what` names the contents of the file in error messages.
+pub(crate) fn load<T: Default + DeserializeOwned>(
+    path: &Path,
+    what: &str,
+) -> Result<T, EspressoKeystoreError> {
+    match fs::read(path) {
+        Ok(bytes) => bincode::deserialize(&bytes).map_err(|err| error("read", what, err)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(error("read", what, err)),
+    }
+}
+
+/// Replace the value stored at `path` with `value`, creating `path` if necessary.
+///
+/// The value is written to a temporary file, which is flushed to disk before it is renamed over
+/// `path`, so that after a crash `path` holds either the old value or the new one.
+pub(crate) fn save<T: Serialize>(
+    path: &Path,
+    value: &T,
+    what: &str,
+) -> Result<(), EspressoKeystoreError> {
+    let write = || -> std::io::Result<()> {
+        let bytes = bincode::serialize(value)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // Make the rename itself durable. Directories cannot be opened as files on every platform.
+        if let (true, Some(dir)) = (cfg!(unix), dir) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    };
+    write().map_err(|err| error("write", what, err))
+}
+
+/// An error reporting that `action` failed on the file holding `what`.
+pub(crate) fn error(action: &str, what: &str, err: impl Display) -> EspressoKeystoreError {
+    EspressoKeystoreError::Failed {
+        msg: format!("failed to {} {}: {}", action, what, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = TempDir::new("persistence").unwrap();
+        let path = dir.path().join("nested").join("state");
+
+        let mut state: BTreeMap<String, u64> = load(&path, "state").unwrap();
+        assert!(state.is_empty());
+        state.insert("count".into(), 1);
+        save(&path, &state, "state").unwrap();
+        state.insert("count".into(), 2);
+        save(&path, &state, "state").unwrap();
+        assert_eq!(
+            load::<BTreeMap<String, u64>>(&path, "state").unwrap(),
+            state
+        );
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::write(&path, b"\xff").unwrap();
+        let err = load::<BTreeMap<String, u64>>(&path, "state").unwrap_err();
+        assert!(err.to_string().contains("failed to read state"));
+    }
+}
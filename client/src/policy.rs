@@ -0,0 +1,318 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Spending limits for keystores.
+//!
+//! A [SpendingPolicy] describes guardrails on outgoing transfers: a maximum amount per transfer, a
+//! cap on the total amount transferred in any 24 hour window, and lists of allowed or denied
+//! receivers. Policies are distributed as [SignedPolicy]s, signed by an administrator key, so that
+//! a policy file cannot be loosened by anyone who merely has access to the keystore's storage.
+//!
+//! A [PolicyEngine] enforces a policy on the transfers made through
+//! [PolicyEngine::transfer], and persists the history of recent outflows so that daily caps
+//! survive restarts.
+//!
+//! The policy is a guardrail for applications, not a security boundary. It does not apply to
+//! transfers made directly with the keystore (for example, with `keystore.transfer`), which bypass
+//! the engine entirely. The outflows file is not signed, so anyone who can delete or replace it can
+//! reset the daily caps.
+
+use crate::{persistence, redact::sensitive, EspressoKeystore, EspressoKeystoreError};
+use espresso_core::ledger::EspressoLedger;
+use jf_cap::{
+    keys::{UserAddress, UserKeyPair, UserPubKey},
+    structs::AssetCode,
+    Signature,
+};
+use seahorse::{ledger_state::TransactionUID, KeystoreBackend, RecordAmount};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// The window over which [SpendingPolicy::daily_limit] is enforced.
+pub const DAILY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpendingPolicy {
+    /// The maximum total amount of each asset which may be sent in a single transfer.
+    pub max_per_transfer: HashMap<AssetCode, u128>,
+    /// The maximum total amount of each asset which may be sent in any 24 hour window.
+    pub daily_limit: HashMap<AssetCode, u128>,
+    /// If present, transfers may only pay these addresses.
+    pub allowed_receivers: Option<HashSet<UserAddress>>,
+    /// Transfers may never pay these addresses.
+    pub denied_receivers: HashSet<UserAddress>,
+}
+
+/// The ways in which a transfer can violate a [SpendingPolicy].
+#[derive(Clone, Debug, PartialEq, Eq, Snafu)]
+pub enum PolicyViolation {
+    #[snafu(display(
+        "transfer of {} {} exceeds the per-transfer limit of {}",
+        amount,
+        asset,
+        limit
+    ))]
+    TransferLimit {
+        asset: AssetCode,
+        amount: u128,
+        limit: u128,
+    },
+    #[snafu(display(
+        "transfer of {} {} would exceed the daily limit of {} ({} already sent)",
+        amount,
+        asset,
+        limit,
+        spent
+    ))]
+    DailyLimit {
+        asset: AssetCode,
+        amount: u128,
+        spent: u128,
+        limit: u128,
+    },
+    #[snafu(display("receiver {} is not in the allow list", address))]
+    ReceiverNotAllowed { address: UserAddress },
+    #[snafu(display("receiver {} is in the deny list", address))]
+    ReceiverDenied { address: UserAddress },
+    #[snafu(display("the policy is not signed by the trusted administrator"))]
+    InvalidSignature,
+}
+
+#[derive(Debug, Snafu)]
+pub enum PolicyError {
+    #[snafu(display("policy violation: {}", source))]
+    Violation { source: PolicyViolation },
+    #[snafu(display("{}", source))]
+    Keystore { source: EspressoKeystoreError },
+}
+
+impl From<PolicyViolation> for PolicyError {
+    fn from(source: PolicyViolation) -> Self {
+        Self::Violation { source }
+    }
+}
+
+impl From<EspressoKeystoreError> for PolicyError {
+    fn from(source: EspressoKeystoreError) -> Self {
+        Self::Keystore { source }
+    }
+}
+
+/// A [SpendingPolicy] signed by an administrator.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedPolicy {
+    pub policy_bytes: Vec<u8>,
+    pub sig: Signature,
+}
+
+impl SignedPolicy {
+    pub fn sign(policy: &SpendingPolicy, admin: &UserKeyPair) -> Self {
+        let policy_bytes = bincode::serialize(policy).unwrap();
+        let sig = admin.sign(&policy_bytes);
+        Self { policy_bytes, sig }
+    }
+
+    /// Check the signature against `admin` and extract the policy.
+    pub fn verify(&self, admin: &UserPubKey) -> Result<SpendingPolicy, PolicyViolation> {
+        admin
+            .verify_sig(&self.policy_bytes, &self.sig)
+            .map_err(|_| PolicyViolation::InvalidSignature)?;
+        bincode::deserialize(&self.policy_bytes).map_err(|_| PolicyViolation::InvalidSignature)
+    }
+}
+
+/// Enforces a [SpendingPolicy] on transfers.
+#[derive(Debug)]
+pub struct PolicyEngine {
+    policy: SpendingPolicy,
+    // File in which recent outflows are persisted.
+    path: PathBuf,
+    outflows: Vec<(SystemTime, AssetCode, u128)>,
+}
+
+impl PolicyEngine {
+    /// Enforce `policy`, which must be signed by `admin`.
+    ///
+    /// Recent outflows are loaded from, and persisted to, `outflows_path`.
+    pub fn load(
+        policy: &SignedPolicy,
+        admin: &UserPubKey,
+        outflows_path: impl Into<PathBuf>,
+    ) -> Result<Self, PolicyError> {
+        let policy = policy.verify(admin)?;
+        let path = outflows_path.into();
+        let outflows = persistence::load(&path, "policy outflows")?;
+        Ok(Self {
+            policy,
+            path,
+            outflows,
+        })
+    }
+
+    pub fn policy(&self) -> &SpendingPolicy {
+        &self.policy
+    }
+
+    /// The total amount of `asset` sent in the last 24 hours.
+    pub fn spent_today(&self, asset: &AssetCode) -> u128 {
+        let now = SystemTime::now();
+        self.outflows
+            .iter()
+            .filter(|(time, outflow_asset, _)| {
+                outflow_asset == asset && Self::within_window(*time, now)
+            })
+            .fold(0u128, |total, (_, _, amount)| total.saturating_add(*amount))
+    }
+
+    /// Check whether a transfer of `asset` to `receivers` is allowed.
+    pub fn check(
+        &self,
+        asset: &AssetCode,
+        receivers: &[(UserPubKey, RecordAmount)],
+    ) -> Result<(), PolicyViolation> {
+        for (pub_key, _) in receivers {
+            let address = pub_key.address();
+            if self.policy.denied_receivers.contains(&address) {
+                return Err(PolicyViolation::ReceiverDenied { address });
+            }
+            if let Some(allowed) = &self.policy.allowed_receivers {
+                if !allowed.contains(&address) {
+                    return Err(PolicyViolation::ReceiverNotAllowed { address });
+                }
+            }
+        }
+
+        let amount = Self::total(receivers);
+        if let Some(limit) = self.policy.max_per_transfer.get(asset) {
+            if amount > *limit {
+                return Err(PolicyViolation::TransferLimit {
+                    asset: *asset,
+                    amount,
+                    limit: *limit,
+                });
+            }
+        }
+        if let Some(limit) = self.policy.daily_limit.get(asset) {
+            let spent = self.spent_today(asset);
+            if spent.saturating_add(amount) > *limit {
+                return Err(PolicyViolation::DailyLimit {
+                    asset: *asset,
+                    amount,
+                    spent,
+                    limit: *limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Transfer `asset` to `receivers`, if the policy allows it.
+    ///
+    /// Once the transfer has been submitted, its receipt is returned even if the outflow cannot be
+    /// persisted, so that callers do not retry a transfer which was already made. The outflow still
+    /// counts against the daily caps until the engine is reloaded.
+    pub async fn transfer<'a, Backend, Meta>(
+        &mut self,
+        keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+        sender: Option<&UserAddress>,
+        asset: &AssetCode,
+        receivers: &[(UserPubKey, RecordAmount)],
+        fee: RecordAmount,
+    ) -> Result<TransactionUID<EspressoLedger>, PolicyError>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        self.check(asset, receivers)?;
+        let receipt = keystore.transfer(sender, asset, receivers, fee).await?;
+        let amount = Self::total(receivers);
+        if let Err(err) = self.record(*asset, amount) {
+            warn!(
+                "transfer of {} was submitted, but its outflow was not saved: {}",
+                sensitive(&amount),
+                sensitive(&err)
+            );
+        }
+        Ok(receipt)
+    }
+
+    fn record(&mut self, asset: AssetCode, amount: u128) -> Result<(), EspressoKeystoreError> {
+        let now = SystemTime::now();
+        // Outflows older than the window no longer count against any limit.
+        self.outflows
+            .retain(|(time, _, _)| Self::within_window(*time, now));
+        self.outflows.push((now, asset, amount));
+        persistence::save(&self.path, &self.outflows, "policy outflows")
+    }
+
+    fn total(receivers: &[(UserPubKey, RecordAmount)]) -> u128 {
+        receivers.iter().fold(0u128, |total, (_, amount)| {
+            total.saturating_add(amount.as_u128())
+        })
+    }
+
+    fn within_window(time: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(time)
+            .map(|age| age < DAILY_WINDOW)
+            // Outflows from the future (if the clock went backwards) still count.
+            .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_policy_checks() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let admin = UserKeyPair::generate(&mut rng);
+        let friend = UserKeyPair::generate(&mut rng).pub_key();
+        let stranger = UserKeyPair::generate(&mut rng).pub_key();
+        let native = AssetCode::native();
+
+        let policy = SpendingPolicy {
+            max_per_transfer: vec![(native, 100)].into_iter().collect(),
+            daily_limit: vec![(native, 150)].into_iter().collect(),
+            allowed_receivers: Some(vec![friend.address()].into_iter().collect()),
+            denied_receivers: Default::default(),
+        };
+        let signed = SignedPolicy::sign(&policy, &admin);
+        assert_eq!(
+            signed.verify(&stranger).unwrap_err(),
+            PolicyViolation::InvalidSignature
+        );
+
+        let dir = TempDir::new("spending_policy").unwrap();
+        let mut engine =
+            PolicyEngine::load(&signed, &admin.pub_key(), dir.path().join("outflows")).unwrap();
+        engine
+            .check(&native, &[(friend.clone(), 100u64.into())])
+            .unwrap();
+        assert!(matches!(
+            engine.check(&native, &[(friend.clone(), 101u64.into())]),
+            Err(PolicyViolation::TransferLimit { .. })
+        ));
+        assert!(matches!(
+            engine.check(&native, &[(stranger, 1u64.into())]),
+            Err(PolicyViolation::ReceiverNotAllowed { .. })
+        ));
+
+        engine.record(native, 100).unwrap();
+        assert!(matches!(
+            engine.check(&native, &[(friend.clone(), 60u64.into())]),
+            Err(PolicyViolation::DailyLimit { spent: 100, .. })
+        ));
+
+        // Outflows survive a restart.
+        let engine =
+            PolicyEngine::load(&signed, &admin.pub_key(), dir.path().join("outflows")).unwrap();
+        assert_eq!(engine.spent_today(&native), 100);
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! An on-disk cache of CAP proving keys.
+//!
+//! Preprocessing the mint, transfer, and freeze circuits from the universal parameters is one of
+//! the slowest parts of starting a keystore, and it has to be redone every time the keystore is
+//! opened. [ProverKeyStore] avoids this by caching each proving key in its own file. Only the
+//! circuits which the ledger actually supports (as indicated by the verifying keys in the ledger
+//! state) are loaded, and cached keys for circuits the ledger no longer supports are evicted.
+//!
+//! If a key is not in the cache, the store can optionally download it from a configurable URL
+//! before falling back to preprocessing it locally. A bad proving key cannot compromise funds,
+//! since it can only produce proofs which validators will reject, but it can still deny service:
+//! every transaction built with it fails. Downloads are therefore only accepted if the server
+//! responds successfully and the key matches the SHA3-256 digest published next to it (see
+//! [ProverKeyStore::with_download_url]), which catches truncated, stale, and corrupted downloads.
+//! The digest comes from the same server as the key, so the download URL must still be trusted.
+//!
+//! Each cached key is named after a digest of the verifying key it corresponds to, so a cached
+//! key is only ever used with the verifying key it was cached for. If the ledger's verifying keys
+//! change, the old proving keys no longer match any of them and are evicted, rather than being
+//! loaded on every start and producing proofs which are always rejected.
+
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use espresso_core::{ledger::EspressoLedger, universal_params::MERKLE_HEIGHT};
+use jf_cap::proof::{
+    freeze::{self, FreezeProvingKey},
+    mint,
+    transfer::{self, TransferProvingKey},
+    UniversalParam,
+};
+use key_set::{OrderByOutputs, ProverKeySet, SizedKey, VerifierKeySet};
+use lazy_static::lazy_static;
+use regex::Regex;
+use seahorse::{CryptoSnafu, KeystoreError};
+use serde::Serialize;
+use sha3::{Digest, Sha3_256};
+use snafu::ResultExt;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use surf_disco::Url;
+use tracing::{info, warn};
+
+/// Identifies one of the proving keys managed by a [ProverKeyStore].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProverKeyId {
+    Mint,
+    Transfer { inputs: usize, outputs: usize },
+    Freeze { inputs: usize },
+}
+
+lazy_static! {
+    // Matches the names produced by [ProverKeyId::file_name], as well as names without a digest,
+    // which were used by earlier versions of the cache. Only files matching this pattern are ever
+    // evicted, since the cache directory may be shared with other files.
+    static ref CACHED_KEY_FILE: Regex =
+        Regex::new(r"^(mint|xfr-\d+x\d+|freeze-\d+)-h\d+(-[0-9a-f]{16})?\.bin$").unwrap();
+}
+
+impl ProverKeyId {
+    /// The name of the file this key is cached in, relative to the cache directory.
+    ///
+    /// `vk_digest` identifies the verifying key the proving key corresponds to (see
+    /// [verifying_key_digest]). The same name is used to locate the key relative to the download
+    /// URL.
+    pub fn file_name(&self, vk_digest: &str) -> String {
+        format!("{}-h{}-{}.bin", self, MERKLE_HEIGHT, vk_digest)
+    }
+}
+
+/// A short digest of a verifying key, used to name the matching proving key.
+pub fn verifying_key_digest(vk: &impl Serialize) -> String {
+    // Verifying keys always serialize, so an error here would be a bug in the key types.
+    let bytes = bincode::serialize(vk).expect("verifying keys are serializable");
+    hex::encode(&Sha3_256::digest(&bytes)[..8])
+}
+
+impl Display for ProverKeyId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Mint => write!(f, "mint"),
+            Self::Transfer { inputs, outputs } => write!(f, "xfr-{}x{}", inputs, outputs),
+            Self::Freeze { inputs } => write!(f, "freeze-{}", inputs),
+        }
+    }
+}
+
+/// Loads proving keys from an on-disk cache, a remote server, or by preprocessing.
+///
+/// A store with neither a cache directory nor a download URL simply preprocesses every key, which
+/// is the behavior of a keystore backend without a store.
+pub struct ProverKeyStore<'a> {
+    univ_param: &'a UniversalParam,
+    cache_dir: Option<PathBuf>,
+    download_url: Option<Url>,
+}
+
+impl<'a> ProverKeyStore<'a> {
+    pub fn new(univ_param: &'a UniversalParam) -> Self {
+        Self {
+            univ_param,
+            cache_dir: None,
+            download_url: None,
+        }
+    }
+
+    /// Cache keys in `dir`, creating it if necessary.
+    ///
+    /// Other files in `dir` are left alone; only files named like cached proving keys are evicted.
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Download keys which are not cached from `url`.
+    ///
+    /// Each key is fetched from `url` joined with its [ProverKeyId::file_name], so a server can
+    /// publish keys for several versions of the ledger side by side. Alongside each key, the server
+    /// must publish the hex-encoded SHA3-256 digest of the key file, at the same name with a
+    /// `.sha3` suffix. If the download fails or does not match its digest, the key is preprocessed
+    /// locally instead, and the download is never cached.
+    pub fn with_download_url(mut self, url: Url) -> Self {
+        self.download_url = Some(url);
+        self
+    }
+
+    /// Load proving keys matching each of the verifying keys in `verif_crs`.
+    ///
+    /// Cached keys which do not match any of the verifying keys in `verif_crs` are evicted from the
+    /// cache.
+    pub async fn load(
+        &self,
+        verif_crs: &VerifierKeySet,
+    ) -> Result<ProverKeySet<'a, OrderByOutputs>, KeystoreError<EspressoLedger>> {
+        let univ_param = self.univ_param;
+
+        let mut used = HashSet::new();
+        let mint_name = ProverKeyId::Mint.file_name(&verifying_key_digest(&verif_crs.mint));
+        used.insert(mint_name.clone());
+        let mint = self
+            .load_key(ProverKeyId::Mint, &mint_name, || {
+                Ok(mint::preprocess(univ_param, MERKLE_HEIGHT)
+                    .context(CryptoSnafu)?
+                    .0)
+            })
+            .await?;
+
+        let mut xfr = Vec::new();
+        for k in verif_crs.xfr.iter() {
+            let (inputs, outputs) = (k.num_inputs(), k.num_outputs());
+            let id = ProverKeyId::Transfer { inputs, outputs };
+            let name = id.file_name(&verifying_key_digest(k));
+            used.insert(name.clone());
+            xfr.push(
+                self.load_key::<TransferProvingKey>(id, &name, || {
+                    Ok(
+                        transfer::preprocess(univ_param, inputs, outputs, MERKLE_HEIGHT)
+                            .context(CryptoSnafu)?
+                            .0,
+                    )
+                })
+                .await?,
+            );
+        }
+
+        let mut freeze = Vec::new();
+        for k in verif_crs.freeze.iter() {
+            let inputs = k.num_inputs();
+            let id = ProverKeyId::Freeze { inputs };
+            let name = id.file_name(&verifying_key_digest(k));
+            used.insert(name.clone());
+            freeze.push(
+                self.load_key::<FreezeProvingKey>(id, &name, || {
+                    Ok(freeze::preprocess(univ_param, inputs, MERKLE_HEIGHT)
+                        .context(CryptoSnafu)?
+                        .0)
+                })
+                .await?,
+            );
+        }
+
+        if let Some(dir) = &self.cache_dir {
+            evict(dir, &used);
+        }
+
+        Ok(ProverKeySet {
+            mint,
+            xfr: xfr.into_iter().collect(),
+            freeze: freeze.into_iter().collect(),
+        })
+    }
+
+    async fn load_key<K: CanonicalSerialize + CanonicalDeserialize>(
+        &self,
+        id: ProverKeyId,
+        file_name: &str,
+        preprocess: impl FnOnce() -> Result<K, KeystoreError<EspressoLedger>>,
+    ) -> Result<K, KeystoreError<EspressoLedger>> {
+        let path = self.cache_dir.as_ref().map(|dir| dir.join(file_name));
+
+        if let Some(path) = &path {
+            if path.exists() {
+                match read_cached(path) {
+                    Ok(key) => return Ok(key),
+                    Err(msg) => warn!("ignoring corrupt cached proving key {}: {}", id, msg),
+                }
+            }
+        }
+
+        let key = match self.download(id, file_name).await {
+            Some(key) => key,
+            None => {
+                info!("preprocessing proving key {}", id);
+                preprocess()?
+            }
+        };
+
+        if let Some(path) = &path {
+            if let Err(msg) = write_cached(path, &key) {
+                warn!("failed to cache proving key {}: {}", id, msg);
+            }
+        }
+        Ok(key)
+    }
+
+    async fn download<K: CanonicalDeserialize>(
+        &self,
+        id: ProverKeyId,
+        file_name: &str,
+    ) -> Option<K> {
+        let base = self.download_url.as_ref()?;
+        let url = base.join(file_name).ok()?;
+        let digest_url = base.join(&format!("{}.sha3", file_name)).ok()?;
+        info!("downloading proving key {} from {}", id, url);
+        let fetched = async {
+            let bytes = fetch(&url).await?;
+            let digest = String::from_utf8(fetch(&digest_url).await?)
+                .map_err(|err| format!("digest is not text: {}", err))?;
+            check_digest(&bytes, &digest)?;
+            Ok::<_, String>(bytes)
+        };
+        let bytes = match fetched.await {
+            Ok(bytes) => bytes,
+            Err(msg) => {
+                warn!("failed to download proving key {}: {}", id, msg);
+                return None;
+            }
+        };
+        match K::deserialize(bytes.as_slice()) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                warn!("downloaded proving key {} is malformed: {}", id, err);
+                None
+            }
+        }
+    }
+}
+
+/// Fetch the body of `url`, failing unless the server responds with a success status.
+async fn fetch(url: &Url) -> Result<Vec<u8>, String> {
+    let mut res = surf::get(url.as_str())
+        .await
+        .map_err(|err| err.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{} responded with status {}", url, res.status()));
+    }
+    res.body_bytes().await.map_err(|err| err.to_string())
+}
+
+/// Check that `bytes` hash to the hex-encoded SHA3-256 digest `expected`.
+fn check_digest(bytes: &[u8], expected: &str) -> Result<(), String> {
+    let actual = hex::encode(Sha3_256::digest(bytes));
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "digest mismatch: expected {}, got {}",
+            expected.trim(),
+            actual
+        ))
+    }
+}
+
+/// Remove cached keys from `dir` which are not named in `keep`.
+///
+/// Eviction is best-effort: failures are logged but otherwise ignored, since a stale key in the
+/// cache only wastes disk space.
+fn evict(dir: &Path, keep: &HashSet<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("unable to read prover key cache {:?}: {}", dir, err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if CACHED_KEY_FILE.is_match(&name) && !keep.contains(&name) {
+            info!("evicting unused proving key {}", name);
+            if let Err(err) = fs::remove_file(entry.path()) {
+                warn!("failed to evict proving key {}: {}", name, err);
+            }
+        }
+    }
+}
+
+fn read_cached<K: CanonicalDeserialize>(path: &Path) -> Result<K, String> {
+    let bytes = fs::read(path).map_err(|err| err.to_string())?;
+    K::deserialize(bytes.as_slice()).map_err(|err| err.to_string())
+}
+
+fn write_cached<K: CanonicalSerialize>(path: &Path, key: &K) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let mut bytes = Vec::new();
+    key.serialize(&mut bytes).map_err(|err| err.to_string())?;
+    let tmp_path = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // Make the rename itself durable, as in `crate::persistence`.
+        if let (true, Some(dir)) = (cfg!(unix), path.parent()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    };
+    write().map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_file_names() {
+        let id = ProverKeyId::Transfer {
+            inputs: 2,
+            outputs: 3,
+        };
+        let name = id.file_name("0123456789abcdef");
+        assert_eq!(
+            name,
+            format!("xfr-2x3-h{}-0123456789abcdef.bin", MERKLE_HEIGHT)
+        );
+        assert!(CACHED_KEY_FILE.is_match(&name));
+        assert!(CACHED_KEY_FILE.is_match(&ProverKeyId::Mint.file_name("0123456789abcdef")));
+        assert!(CACHED_KEY_FILE
+            .is_match(&ProverKeyId::Freeze { inputs: 2 }.file_name("0123456789abcdef")));
+        assert!(!CACHED_KEY_FILE.is_match("keystore.bin"));
+
+        // Different verifying keys give different names.
+        assert_ne!(verifying_key_digest(&1u64), verifying_key_digest(&2u64));
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = TempDir::new("prover_key_cache").unwrap();
+        let used = ProverKeyId::Mint.file_name("0123456789abcdef");
+        let stale = ProverKeyId::Mint.file_name("fedcba9876543210");
+        let legacy = format!("xfr-2x2-h{}.bin", MERKLE_HEIGHT);
+        let unrelated = "data.bin";
+        for name in [used.as_str(), stale.as_str(), legacy.as_str(), unrelated] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        evict(dir.path(), &vec![used.clone()].into_iter().collect());
+        assert!(dir.path().join(&used).exists());
+        assert!(!dir.path().join(&stale).exists());
+        assert!(!dir.path().join(&legacy).exists());
+        assert!(dir.path().join(unrelated).exists());
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let dir = TempDir::new("prover_key_cache").unwrap();
+        let path = dir
+            .path()
+            .join("keys")
+            .join(ProverKeyId::Mint.file_name("0123456789abcdef"));
+        let key = vec![1u64, 2, 3];
+        write_cached(&path, &key).unwrap();
+        assert_eq!(read_cached::<Vec<u64>>(&path).unwrap(), key);
+
+        // A corrupt file is an error, not a panic.
+        fs::write(&path, [1]).unwrap();
+        assert!(read_cached::<Vec<u64>>(&path).is_err());
+    }
+
+    #[test]
+    fn test_check_digest() {
+        let key = b"proving key";
+        let digest = hex::encode(Sha3_256::digest(key));
+        assert!(check_digest(key, &digest).is_ok());
+        // Servers may publish the digest with a trailing newline or in upper case.
+        assert!(check_digest(key, &format!("{}\n", digest.to_uppercase())).is_ok());
+        // A truncated download does not match its digest.
+        assert!(check_digest(&key[..4], &digest).is_err());
+        assert!(check_digest(key, "").is_err());
+    }
+}
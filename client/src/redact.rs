@@ -0,0 +1,133 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Redaction of sensitive values in logs.
+//!
+//! Addresses, public keys, amounts, and transaction hashes identify users and their activity, and
+//! error messages and request paths often contain them. When the client is built with the
+//! `redact-logs` feature, all of these are replaced by a placeholder in log messages, so that
+//! operators can ship client logs to shared infrastructure without leaking user data.
+
+use std::fmt::Display;
+
+/// Format `value` for logging, unless sensitive values are being redacted.
+#[cfg(not(feature = "redact-logs"))]
+pub(crate) fn sensitive(value: &impl Display) -> String {
+    value.to_string()
+}
+
+/// Format `value` for logging, unless sensitive values are being redacted.
+#[cfg(feature = "redact-logs")]
+pub(crate) fn sensitive(_value: &impl Display) -> String {
+    "<redacted>".to_string()
+}
+
+#[cfg(all(test, feature = "redact-logs", feature = "slow-tests"))]
+mod tests {
+    use crate::{
+        batch::transfer_many,
+        scheduler::{Recurrence, Scheduler, TransferSpec},
+        testing::network::FundedNetwork,
+    };
+    use jf_cap::structs::AssetCode;
+    use seahorse::{ledger_state::TransactionStatus, RecordAmount};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+    use tempdir::TempDir;
+    use tracing::{instrument::WithSubscriber, Level};
+
+    /// A log writer which keeps everything written to it.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_logs_are_redacted() {
+        let mut network = FundedNetwork::new(3).await;
+        let mut receiver = network.new_keystore().await;
+        let receiver_key = receiver
+            .generate_sending_account("receiver".into(), None)
+            .await
+            .unwrap();
+        let faucet = network.faucet.address();
+        let dir = TempDir::new("redact").unwrap();
+        // Distinctive amounts, which cannot be mistaken for anything else in the logs.
+        let scheduled = 123_457u64;
+        let batched = 234_567u64;
+
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(Level::DEBUG)
+            .with_ansi(false)
+            .with_writer({
+                let capture = capture.clone();
+                move || capture.clone()
+            })
+            .finish();
+        async {
+            let mut scheduler = Scheduler::load(dir.path().join("schedules")).unwrap();
+            scheduler
+                .schedule_transfer(
+                    TransferSpec {
+                        sender: Some(faucet.clone()),
+                        asset: AssetCode::native(),
+                        receiver: receiver_key.clone(),
+                        amount: RecordAmount::from(scheduled),
+                        fee: RecordAmount::from(1u64),
+                    },
+                    SystemTime::now(),
+                    Recurrence::Once,
+                )
+                .unwrap();
+            let receipts = scheduler.run_due(&mut network.keystore).await.unwrap();
+            assert_eq!(receipts.len(), 1);
+            assert!(matches!(
+                network
+                    .keystore
+                    .await_transaction(&receipts[0].1)
+                    .await
+                    .unwrap(),
+                TransactionStatus::Retired
+            ));
+
+            transfer_many(
+                &mut network.keystore,
+                &faucet,
+                &AssetCode::native(),
+                vec![(receiver_key.clone(), RecordAmount::from(batched))],
+                RecordAmount::from(1u64),
+            )
+            .await
+            .unwrap();
+        }
+        .with_subscriber(subscriber)
+        .await;
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("<redacted>"), "{}", logs);
+        for secret in [
+            faucet.to_string(),
+            receiver_key.address().to_string(),
+            scheduled.to_string(),
+            batched.to_string(),
+        ] {
+            assert!(
+                !logs.contains(&secret),
+                "{} appears in logs:\n{}",
+                secret,
+                logs
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Rotation of sending keys.
+//!
+//! [rotate_user_key] replaces a sending key which may have been exposed with a fresh one: it
+//! generates a new sending account, registers it in the address book, and moves every spendable
+//! record from the old account to the new one.
+//!
+//! The old key is not removed from the keystore. The keystore keeps scanning the ledger for
+//! records sent to the old address, so payments from counterparties who have not yet learned the
+//! new address are still received, and can be swept by rotating again.
+
+use crate::{
+    redact::sensitive,
+    spendable::{spendable_records, total},
+    EspressoKeystore, EspressoKeystoreError,
+};
+use espresso_core::{ledger::EspressoLedger, transfer_plan::max_asset_inputs};
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::AssetCode,
+};
+use seahorse::{
+    ledger_state::{TransactionStatus, TransactionUID},
+    KeystoreBackend, RecordAmount,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+/// The result of a successful [rotate_user_key].
+#[derive(Clone, Debug)]
+pub struct KeyRotation {
+    /// The key which replaces the old one.
+    pub new_key: UserPubKey,
+    /// The transfers which moved funds from the old account, in the order they were made.
+    pub receipts: Vec<TransactionUID<EspressoLedger>>,
+}
+
+/// Replace the sending key for `old_address` with a new one and move its funds.
+///
+/// Each non-native asset held by the old account is moved first, and the native asset is moved
+/// last, less the fees. An asset whose records do not all fit in one transfer is moved in as few
+/// transfers as the supported transfer sizes allow. Every transfer pays `fee` from the old account,
+/// and is waited for before the next one starts, so that the change from one transfer can pay the
+/// fee for the next. Frozen records, and records which are on hold because they are inputs to
+/// pending transactions, are left behind.
+///
+/// If a transfer fails, the error is returned, and the funds which were already moved stay in the
+/// new account. Rotating again moves the rest, after generating yet another key.
+pub async fn rotate_user_key<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    old_address: &UserAddress,
+    description: String,
+    fee: RecordAmount,
+) -> Result<KeyRotation, EspressoKeystoreError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    if !keystore
+        .sending_keys()
+        .await
+        .iter()
+        .any(|key| key.address() == *old_address)
+    {
+        return Err(EspressoKeystoreError::Failed {
+            msg: format!("{} is not a sending account of this keystore", old_address),
+        });
+    }
+
+    // Generating a sending account also publishes it to the address book.
+    let new_key = keystore.generate_sending_account(description, None).await?;
+    info!(
+        "rotating sending key {} to {}",
+        sensitive(old_address),
+        sensitive(&new_key.address())
+    );
+
+    let mut receipts = Vec::new();
+    let assets: HashSet<AssetCode> = keystore
+        .records()
+        .await
+        .into_iter()
+        .filter(|record| record.pub_key().address() == *old_address)
+        .map(|record| record.asset_code())
+        .collect();
+    for asset in assets {
+        if asset != AssetCode::native() {
+            move_asset(keystore, old_address, &new_key, &asset, fee, &mut receipts).await?;
+        }
+    }
+    // Move the native asset last, once all the other transfers have paid their fees.
+    move_asset(
+        keystore,
+        old_address,
+        &new_key,
+        &AssetCode::native(),
+        fee,
+        &mut receipts,
+    )
+    .await?;
+
+    Ok(KeyRotation { new_key, receipts })
+}
+
+/// Move the spendable records of `asset` owned by `old_address` to `new_key`.
+///
+/// Each transfer moves as many of the largest remaining records as a single transfer can spend.
+/// Input selection takes the largest records first, so transferring their combined amount (less
+/// the fee, for the native asset) spends exactly those records, and creates one record for the new
+/// key.
+async fn move_asset<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    old_address: &UserAddress,
+    new_key: &UserPubKey,
+    asset: &AssetCode,
+    fee: RecordAmount,
+    receipts: &mut Vec<TransactionUID<EspressoLedger>>,
+) -> Result<(), EspressoKeystoreError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let native = *asset == AssetCode::native();
+    let chunk_size = max_asset_inputs(native, 1).ok_or_else(|| EspressoKeystoreError::Failed {
+        msg: "no supported transfer size can move records to a new key".into(),
+    })?;
+    loop {
+        let records = spendable_records(keystore, Some(old_address), asset).await;
+        let chunk = &records[..chunk_size.min(records.len())];
+        let amount = if native {
+            total(chunk).saturating_sub(fee.as_u128())
+        } else {
+            total(chunk)
+        };
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let receipt = keystore
+            .transfer(
+                Some(old_address),
+                asset,
+                &[(new_key.clone(), RecordAmount::from(amount))],
+                fee,
+            )
+            .await?;
+        await_retired(keystore, &receipt).await?;
+        receipts.push(receipt);
+    }
+}
+
+async fn await_retired<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    receipt: &TransactionUID<EspressoLedger>,
+) -> Result<(), EspressoKeystoreError>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    match keystore.await_transaction(receipt).await? {
+        TransactionStatus::Retired => Ok(()),
+        status => Err(EspressoKeystoreError::Failed {
+            msg: format!("key rotation transfer did not complete: {}", status),
+        }),
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::FundedNetwork;
+    use primitive_types::U256;
+
+    #[async_std::test]
+    async fn test_rotate_fragmented_account() {
+        let mut network = FundedNetwork::new(3).await;
+        let keystore = &mut network.keystore;
+        let faucet = network.faucet.address();
+        let old_key = keystore
+            .generate_sending_account("old".into(), None)
+            .await
+            .unwrap();
+
+        // Give the old account more records than a single transfer can spend.
+        for _ in 0..4 {
+            let receipt = keystore
+                .transfer(
+                    Some(&faucet),
+                    &AssetCode::native(),
+                    &[(old_key.clone(), RecordAmount::from(100u64))],
+                    RecordAmount::from(1u64),
+                )
+                .await
+                .unwrap();
+            await_retired(keystore, &receipt).await.unwrap();
+        }
+        assert_eq!(
+            keystore
+                .balance_breakdown(&old_key.address(), &AssetCode::native())
+                .await,
+            U256::from(400u64)
+        );
+
+        let rotation = rotate_user_key(
+            keystore,
+            &old_key.address(),
+            "new".into(),
+            RecordAmount::from(1u64),
+        )
+        .await
+        .unwrap();
+        // Native transfers spend up to 3 records, so 4 records take 2 transfers.
+        assert_eq!(rotation.receipts.len(), 2);
+        assert_eq!(
+            keystore
+                .balance_breakdown(&old_key.address(), &AssetCode::native())
+                .await,
+            U256::zero()
+        );
+        assert_eq!(
+            keystore
+                .balance_breakdown(&rotation.new_key.address(), &AssetCode::native())
+                .await,
+            U256::from(398u64)
+        );
+    }
+}
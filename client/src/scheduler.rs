@@ -0,0 +1,594 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Scheduled and recurring transfers.
+//!
+//! A [Scheduler] persists transfers which should happen at some point in the future, either once or
+//! repeatedly at a fixed interval, and executes them when they become due. Schedules only run while
+//! a keystore is running the scheduler, either by calling [Scheduler::run_due] directly or by
+//! spawning [Scheduler::run], or [Scheduler::run_until] to be able to stop it cleanly. A schedule
+//! which becomes due while no keystore is running, or while the keystore does not have the funds to
+//! pay it, runs as soon as possible afterwards. Stopping the scheduler does not shut down the
+//! keystore it runs on.
+//!
+//! A recurring schedule runs at most once per call to [Scheduler::run_due], even if several of its
+//! intervals have elapsed since it last ran. Missed intervals are skipped, not paid in bulk.
+//!
+//! Each run is recorded before its transfer is submitted, so a schedule never pays twice for the
+//! same run. If the transfer cannot be built, nothing was submitted, so the run is undone and the
+//! schedule remains due. If the process stops after recording a run and before submitting its
+//! transfer, that run is skipped.
+//!
+//! If submitting a transfer fails, the transfer may still have reached a validator, so the run
+//! stays recorded and the schedule is marked as needing reconciliation. It does not run again until
+//! the caller has checked whether the payment was made and called [Scheduler::reconcile].
+
+use crate::{
+    persistence,
+    redact::sensitive,
+    transfer::{transfer, TransferFailure},
+    EspressoKeystore, EspressoKeystoreError,
+};
+use async_std::{
+    sync::{Arc, Mutex},
+    task::sleep,
+};
+use espresso_core::ledger::EspressoLedger;
+use futures::future::{self, Either, Future};
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::AssetCode,
+};
+use primitive_types::U256;
+use seahorse::{ledger_state::TransactionUID, KeystoreBackend, RecordAmount};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// The transfer to make when a schedule runs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferSpec {
+    /// The account to pay from, or [None] to use any account in the keystore.
+    pub sender: Option<UserAddress>,
+    pub asset: AssetCode,
+    pub receiver: UserPubKey,
+    pub amount: RecordAmount,
+    pub fee: RecordAmount,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recurrence {
+    /// Run once, then remove the schedule.
+    Once,
+    /// Run repeatedly, with at least this much time between runs.
+    Every(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: u64,
+    pub spec: TransferSpec,
+    pub recurrence: Recurrence,
+    /// The earliest time at which this schedule will next run.
+    pub next_run: SystemTime,
+    /// The number of times this schedule has run.
+    pub runs: u64,
+    /// The transfer for the latest run failed during submission, so it is unknown whether it was
+    /// paid. The schedule does not run again until [Scheduler::reconcile] is called.
+    pub needs_reconciliation: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SchedulerState {
+    schedules: BTreeMap<u64, Schedule>,
+    next_id: u64,
+}
+
+/// A persistent collection of [Schedule]s.
+#[derive(Debug)]
+pub struct Scheduler {
+    path: PathBuf,
+    state: SchedulerState,
+}
+
+impl Scheduler {
+    /// Load the schedules stored at `path`.
+    ///
+    /// If `path` does not exist, there are no schedules, and the file will be created when the
+    /// first schedule is added.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, EspressoKeystoreError> {
+        let path = path.into();
+        let state = persistence::load(&path, "schedules")?;
+        Ok(Self { path, state })
+    }
+
+    /// Schedule `spec` to run at `start`, and then according to `recurrence`.
+    ///
+    /// Returns the ID of the new schedule, which can be used to cancel it.
+    pub fn schedule_transfer(
+        &mut self,
+        spec: TransferSpec,
+        start: SystemTime,
+        recurrence: Recurrence,
+    ) -> Result<u64, EspressoKeystoreError> {
+        let id = self.state.next_id;
+        self.state.next_id += 1;
+        self.state.schedules.insert(
+            id,
+            Schedule {
+                id,
+                spec,
+                recurrence,
+                next_run: start,
+                runs: 0,
+                needs_reconciliation: false,
+            },
+        );
+        self.save()?;
+        Ok(id)
+    }
+
+    /// Cancel a schedule, returning it if it existed.
+    pub fn cancel(&mut self, id: u64) -> Result<Option<Schedule>, EspressoKeystoreError> {
+        let schedule = self.state.schedules.remove(&id);
+        if schedule.is_some() {
+            self.save()?;
+        }
+        Ok(schedule)
+    }
+
+    /// Resolve a schedule whose latest run [needs reconciliation](Schedule::needs_reconciliation).
+    ///
+    /// `paid` says whether the transfer for that run was made, which the caller can determine from
+    /// the keystore's transaction history or the receiver. If it was, the run stands; otherwise the
+    /// run is undone and the schedule is due immediately. Returns the updated schedule, or [None]
+    /// if there is no such schedule or a one-off schedule was paid and has been removed.
+    pub fn reconcile(
+        &mut self,
+        id: u64,
+        paid: bool,
+    ) -> Result<Option<Schedule>, EspressoKeystoreError> {
+        let schedule = match self.state.schedules.get_mut(&id) {
+            Some(schedule) if schedule.needs_reconciliation => schedule,
+            schedule => return Ok(schedule.cloned()),
+        };
+        schedule.needs_reconciliation = false;
+        if !paid {
+            schedule.runs -= 1;
+            schedule.next_run = SystemTime::now();
+        }
+        let schedule = if paid && schedule.recurrence == Recurrence::Once {
+            self.state.schedules.remove(&id);
+            None
+        } else {
+            Some(schedule.clone())
+        };
+        self.save()?;
+        Ok(schedule)
+    }
+
+    /// All schedules, ordered by ID.
+    pub fn schedules(&self) -> impl Iterator<Item = &Schedule> {
+        self.state.schedules.values()
+    }
+
+    /// Run every schedule which is due.
+    ///
+    /// A due schedule is skipped, and remains due, if the sending account (or the keystore, if the
+    /// schedule has no sender) does not have enough of the scheduled asset and of the native asset
+    /// to pay the fee, or if the transfer cannot be built. Schedules which need reconciliation are
+    /// skipped. Returns the receipts of the transfers which were submitted, along with the IDs of
+    /// their schedules.
+    pub async fn run_due<'a, Backend, Meta>(
+        &mut self,
+        keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    ) -> Result<Vec<(u64, TransactionUID<EspressoLedger>)>, EspressoKeystoreError>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        let now = SystemTime::now();
+        let due = self
+            .schedules()
+            .filter(|schedule| schedule.next_run <= now)
+            .map(|schedule| schedule.id)
+            .collect::<Vec<_>>();
+
+        let mut receipts = Vec::new();
+        for id in due {
+            let spec = self.state.schedules[&id].spec.clone();
+            if self.state.schedules[&id].needs_reconciliation {
+                info!(
+                    "schedule {} is due, but its last run needs reconciliation",
+                    id
+                );
+                continue;
+            }
+            if !funded(keystore, &spec).await {
+                info!("schedule {} is due, but funds are not available", id);
+                continue;
+            }
+
+            // Record the run before making the transfer, so that the transfer cannot be repeated if
+            // we fail to record it afterwards.
+            let schedule = self.state.schedules[&id].clone();
+            let mut next = schedule.clone();
+            next.runs += 1;
+            match next.recurrence {
+                Recurrence::Once => {
+                    self.state.schedules.remove(&id);
+                }
+                Recurrence::Every(interval) => {
+                    next.next_run = next_run_after(next.next_run, interval, now);
+                    self.state.schedules.insert(id, next);
+                }
+            }
+            if let Err(err) = self.save() {
+                self.state.schedules.insert(id, schedule);
+                return Err(err);
+            }
+
+            info!(
+                "schedule {} is paying {} to {}",
+                id,
+                sensitive(&spec.amount.as_u128()),
+                sensitive(&spec.receiver.address())
+            );
+            match transfer(
+                keystore,
+                spec.sender.as_ref(),
+                &spec.asset,
+                &[(spec.receiver.clone(), spec.amount)],
+                spec.fee,
+            )
+            .await
+            {
+                Ok(receipt) => receipts.push((id, receipt)),
+                Err(TransferFailure::NotSubmitted(err)) => {
+                    warn!("scheduled transfer {} failed: {}", id, sensitive(&err));
+                    // Nothing was submitted, so undo the run and leave the schedule due. If the
+                    // undo cannot be saved, the saved run is only skipped, so carry on.
+                    self.state.schedules.insert(id, schedule);
+                    if let Err(err) = self.save() {
+                        warn!("failed to undo run of schedule {}: {}", id, sensitive(&err));
+                    }
+                }
+                Err(TransferFailure::MaybeSubmitted(err)) => {
+                    warn!(
+                        "scheduled transfer {} may not have been submitted: {}",
+                        id,
+                        sensitive(&err)
+                    );
+                    // The transfer may still be paid, so keep the run, even for a one-off
+                    // schedule, and stop running the schedule until the caller reconciles it.
+                    let mut next = self
+                        .state
+                        .schedules
+                        .remove(&id)
+                        .unwrap_or_else(|| Schedule {
+                            runs: schedule.runs + 1,
+                            ..schedule
+                        });
+                    next.needs_reconciliation = true;
+                    self.state.schedules.insert(id, next);
+                    if let Err(err) = self.save() {
+                        warn!(
+                            "failed to save reconciliation of schedule {}: {}",
+                            id,
+                            sensitive(&err)
+                        );
+                    }
+                }
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Run due schedules every `poll_interval`, forever.
+    ///
+    /// This is meant to be spawned as a background task. The keystore lock is only held while
+    /// schedules are running.
+    pub async fn run<'a, Backend, Meta>(
+        self,
+        keystore: Arc<Mutex<EspressoKeystore<'a, Backend, Meta>>>,
+        poll_interval: Duration,
+    ) where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        self.run_until(keystore, poll_interval, future::pending())
+            .await;
+    }
+
+    /// Run due schedules every `poll_interval`, until `shutdown` completes.
+    ///
+    /// Shutdown never interrupts a run in progress: every due schedule is recorded and its transfer
+    /// submitted before this returns, so stopping the scheduler this way never skips a run. Returns
+    /// the scheduler, which can be run again later.
+    ///
+    /// This only stops the scheduler, not the keystore. It does not flush or close the keystore's
+    /// storage, does not stop the keystore's event sync task, and does not wait for submitted
+    /// transactions to be included in a block. Those are managed by the keystore itself. The
+    /// scheduler's own file needs no flush: it is written in full each time it changes.
+    pub async fn run_until<'a, Backend, Meta>(
+        mut self,
+        keystore: Arc<Mutex<EspressoKeystore<'a, Backend, Meta>>>,
+        poll_interval: Duration,
+        shutdown: impl Future<Output = ()>,
+    ) -> Self
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        let mut shutdown = Box::pin(shutdown);
+        loop {
+            if let Err(err) = self.run_due(&mut *keystore.lock().await).await {
+                warn!("error running scheduled transfers: {}", sensitive(&err));
+            }
+            if let Either::Right(_) =
+                future::select(Box::pin(sleep(poll_interval)), &mut shutdown).await
+            {
+                info!("scheduler shutting down");
+                return self;
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), EspressoKeystoreError> {
+        persistence::save(&self.path, &self.state, "schedules")
+    }
+}
+
+/// Whether the sender of `spec` can pay it, including its fee.
+async fn funded<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    spec: &TransferSpec,
+) -> bool
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let balance = |asset: AssetCode| async move {
+        match &spec.sender {
+            Some(sender) => keystore.balance_breakdown(sender, &asset).await,
+            None => keystore.balance(&asset).await,
+        }
+    };
+    let amount = U256::from(spec.amount.as_u128());
+    let fee = U256::from(spec.fee.as_u128());
+    if spec.asset == AssetCode::native() {
+        balance(spec.asset).await >= amount + fee
+    } else {
+        balance(spec.asset).await >= amount && balance(AssetCode::native()).await >= fee
+    }
+}
+
+/// The first run of a schedule recurring every `interval` from `next_run` which is after `now`.
+///
+/// Intervals are at least one second long, so that a schedule cannot run on every poll.
+fn next_run_after(next_run: SystemTime, interval: Duration, now: SystemTime) -> SystemTime {
+    let interval = interval.max(Duration::from_secs(1)).as_nanos();
+    let behind = now
+        .duration_since(next_run)
+        .unwrap_or(Duration::ZERO)
+        .as_nanos();
+    // Skip every interval which ended at or before `now`.
+    let skip = (behind / interval + 1).saturating_mul(interval);
+    next_run + Duration::new((skip / 1_000_000_000) as u64, (skip % 1_000_000_000) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jf_cap::keys::UserKeyPair;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_reconcile() {
+        let dir = TempDir::new("scheduler").unwrap();
+        let path = dir.path().join("schedules");
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let spec = TransferSpec {
+            sender: None,
+            asset: AssetCode::native(),
+            receiver: UserKeyPair::generate(&mut rng).pub_key(),
+            amount: RecordAmount::from(100u64),
+            fee: RecordAmount::from(1u64),
+        };
+        let later = SystemTime::now() + Duration::from_secs(60 * 60);
+        let mut scheduler = Scheduler::load(&path).unwrap();
+        let once = scheduler
+            .schedule_transfer(spec.clone(), later, Recurrence::Once)
+            .unwrap();
+        let hourly = scheduler
+            .schedule_transfer(spec, later, Recurrence::Every(Duration::from_secs(60 * 60)))
+            .unwrap();
+
+        // Schedules which do not need reconciliation are left alone.
+        assert_eq!(
+            scheduler.reconcile(once, false).unwrap().unwrap().next_run,
+            later
+        );
+
+        // Simulate runs whose submissions failed.
+        for id in [once, hourly] {
+            let schedule = scheduler.state.schedules.get_mut(&id).unwrap();
+            schedule.runs = 1;
+            schedule.needs_reconciliation = true;
+        }
+
+        // A paid one-off schedule is done.
+        assert_eq!(scheduler.reconcile(once, true).unwrap(), None);
+        // An unpaid run is undone and the schedule is due again.
+        let schedule = scheduler.reconcile(hourly, false).unwrap().unwrap();
+        assert_eq!(schedule.runs, 0);
+        assert!(!schedule.needs_reconciliation);
+        assert!(schedule.next_run <= SystemTime::now());
+
+        let scheduler = Scheduler::load(&path).unwrap();
+        assert_eq!(
+            scheduler.schedules().cloned().collect::<Vec<_>>(),
+            vec![schedule]
+        );
+    }
+
+    #[test]
+    fn test_next_run_after() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let minute = Duration::from_secs(60);
+        // Not yet due.
+        assert_eq!(
+            next_run_after(start, minute, start - Duration::from_secs(1)),
+            start + minute
+        );
+        // Due exactly now.
+        assert_eq!(next_run_after(start, minute, start), start + minute);
+        // Many intervals overdue.
+        let now = start + Duration::from_secs(365 * 24 * 60 * 60 + 30);
+        assert_eq!(
+            next_run_after(start, Duration::from_secs(1), now),
+            now + Duration::from_secs(1)
+        );
+        assert_eq!(
+            next_run_after(start, minute, now),
+            start + minute * (365 * 24 * 60 + 1)
+        );
+        // Short intervals are rounded up to one second.
+        assert_eq!(
+            next_run_after(start, Duration::from_millis(1), start),
+            start + Duration::from_secs(1)
+        );
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod slow_tests {
+    use super::*;
+    use crate::testing::network::{retry, FundedNetwork};
+    use seahorse::ledger_state::TransactionStatus;
+    use tempdir::TempDir;
+
+    #[async_std::test]
+    async fn test_run_due() {
+        let mut network = FundedNetwork::new(2).await;
+        let mut receiver = network.new_keystore().await;
+        let receiver_key = receiver
+            .generate_sending_account("receiver".into(), None)
+            .await
+            .unwrap();
+        let dir = TempDir::new("scheduler").unwrap();
+        let path = dir.path().join("schedules");
+        let mut scheduler = Scheduler::load(&path).unwrap();
+
+        let payment = TransferSpec {
+            sender: None,
+            asset: AssetCode::native(),
+            receiver: receiver_key.clone(),
+            amount: RecordAmount::from(100u64),
+            fee: RecordAmount::from(1u64),
+        };
+        let now = SystemTime::now();
+        let once = scheduler
+            .schedule_transfer(payment.clone(), now, Recurrence::Once)
+            .unwrap();
+        // More than the keystore owns, so this one stays due.
+        let unfunded = scheduler
+            .schedule_transfer(
+                TransferSpec {
+                    amount: RecordAmount::from(1u64 << 33),
+                    ..payment.clone()
+                },
+                now,
+                Recurrence::Once,
+            )
+            .unwrap();
+        let receipts = scheduler.run_due(&mut network.keystore).await.unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].0, once);
+        assert!(matches!(
+            network
+                .keystore
+                .await_transaction(&receipts[0].1)
+                .await
+                .unwrap(),
+            TransactionStatus::Retired
+        ));
+
+        let hourly = scheduler
+            .schedule_transfer(
+                payment,
+                now,
+                Recurrence::Every(Duration::from_secs(60 * 60)),
+            )
+            .unwrap();
+        let receipts = scheduler.run_due(&mut network.keystore).await.unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].0, hourly);
+        assert!(matches!(
+            network
+                .keystore
+                .await_transaction(&receipts[0].1)
+                .await
+                .unwrap(),
+            TransactionStatus::Retired
+        ));
+        retry(|| async {
+            receiver
+                .balance_breakdown(&receiver_key.address(), &AssetCode::native())
+                .await
+                == U256::from(200u64)
+        })
+        .await;
+
+        // The runs were persisted: the one-off schedule is gone, the recurring one is not due for
+        // another hour, and the unfunded one is still due.
+        let scheduler = Scheduler::load(&path).unwrap();
+        let schedules = scheduler.schedules().collect::<Vec<_>>();
+        assert_eq!(schedules.len(), 2);
+        assert_eq!(schedules[0].id, unfunded);
+        assert_eq!(schedules[0].runs, 0);
+        assert!(schedules[0].next_run <= SystemTime::now());
+        assert_eq!(schedules[1].id, hourly);
+        assert_eq!(schedules[1].runs, 1);
+        assert!(schedules[1].next_run > SystemTime::now());
+
+        // Funds only count if they belong to the sender. The keystore can pay this, but the
+        // sending account is empty.
+        let mut scheduler = Scheduler::load(&path).unwrap();
+        scheduler.cancel(unfunded).unwrap();
+        let empty = network
+            .keystore
+            .generate_sending_account("empty".into(), None)
+            .await
+            .unwrap();
+        let from_empty = scheduler
+            .schedule_transfer(
+                TransferSpec {
+                    sender: Some(empty.address()),
+                    asset: AssetCode::native(),
+                    receiver: receiver_key,
+                    amount: RecordAmount::from(100u64),
+                    fee: RecordAmount::from(1u64),
+                },
+                SystemTime::now(),
+                Recurrence::Once,
+            )
+            .unwrap();
+        assert!(scheduler
+            .run_due(&mut network.keystore)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            scheduler
+                .schedules()
+                .find(|s| s.id == from_empty)
+                .unwrap()
+                .runs,
+            0
+        );
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Queries for the records a keystore can spend right now.
+
+use crate::EspressoKeystore;
+use espresso_core::ledger::EspressoLedger;
+use jf_cap::{
+    keys::UserAddress,
+    structs::{AssetCode, FreezeFlag},
+};
+use seahorse::{records::Record, KeystoreBackend};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+
+/// The records of `asset` which `account`, or any sending account if `account` is [None], can
+/// spend right now, largest first.
+///
+/// The keystore also tracks records it can view or freeze but not spend, frozen records, and
+/// records which are on hold because they are inputs to pending transactions; none of these are
+/// included. The keystore selects inputs largest first, so a transfer of the combined amount of a
+/// prefix of these records spends exactly that prefix.
+pub(crate) async fn spendable_records<'a, Backend, Meta>(
+    keystore: &EspressoKeystore<'a, Backend, Meta>,
+    account: Option<&UserAddress>,
+    asset: &AssetCode,
+) -> Vec<Record>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let owners: HashSet<UserAddress> = match account {
+        Some(account) => vec![account.clone()].into_iter().collect(),
+        None => keystore
+            .sending_keys()
+            .await
+            .into_iter()
+            .map(|key| key.address())
+            .collect(),
+    };
+    let now = keystore.read().await.state().validator.block_height();
+    let mut records: Vec<Record> = keystore
+        .records()
+        .await
+        .into_iter()
+        .filter(|record| {
+            record.asset_code() == *asset
+                && record.freeze_flag() == FreezeFlag::Unfrozen
+                && !record.on_hold(now)
+                && owners.contains(&record.pub_key().address())
+        })
+        .collect();
+    records.sort_by_key(|record| std::cmp::Reverse(record.amount().as_u128()));
+    records
+}
+
+/// The combined amount of `records`, saturating instead of overflowing.
+pub(crate) fn total<'r>(records: impl IntoIterator<Item = &'r Record>) -> u128 {
+    records.into_iter().fold(0u128, |total, record| {
+        total.saturating_add(record.amount().as_u128())
+    })
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Rate limiting of transaction submissions.
+//!
+//! Validators reject bursts of submissions from a single client, and a busy keystore (such as the
+//! faucet) can easily produce them. A [SubmitQueue] makes submissions wait their turn: at most
+//! [SubmitLimits::max_in_flight] submissions are outstanding at once, consecutive submissions are
+//! spaced at least [SubmitLimits::min_interval] apart, and waiting submissions are served in the
+//! order they arrived.
+//!
+//! A backend submits one transaction at a time, so the limits only matter when a queue is shared:
+//! several backends created with the same `Arc<SubmitQueue>` (see
+//! [NetworkBackend::with_submit_queue](crate::network::NetworkBackend::with_submit_queue)) take
+//! turns submitting through it.
+
+use async_std::{sync::Mutex, task::sleep};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Limits on how quickly a [SubmitQueue] releases submissions.
+#[derive(Clone, Copy, Debug)]
+pub struct SubmitLimits {
+    /// The maximum number of submissions which may be awaiting a response at once.
+    pub max_in_flight: usize,
+    /// The minimum time between the start of consecutive submissions.
+    pub min_interval: Duration,
+}
+
+impl Default for SubmitLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 4,
+            min_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+pub struct SubmitQueue {
+    limits: SubmitLimits,
+    // A counting semaphore: each in-flight submission holds one message in the channel. Blocked
+    // senders are woken in FIFO order.
+    slots: (async_channel::Sender<()>, async_channel::Receiver<()>),
+    // The start time of the most recent submission. Holding this lock while pacing also serializes
+    // the waiters, preserving FIFO order.
+    last_submission: Mutex<Option<Instant>>,
+    waiting: AtomicUsize,
+}
+
+impl SubmitQueue {
+    pub fn new(limits: SubmitLimits) -> Self {
+        Self {
+            limits,
+            slots: async_channel::bounded(limits.max_in_flight.max(1)),
+            last_submission: Mutex::new(None),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> SubmitLimits {
+        self.limits
+    }
+
+    /// The number of submissions waiting for their turn.
+    ///
+    /// A submission which calls [acquire](Self::acquire) next will have this many submissions
+    /// ahead of it.
+    pub fn queue_len(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// The number of submissions currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.slots.0.len()
+    }
+
+    /// Wait for a turn to submit.
+    ///
+    /// The submission is considered in flight until the returned [SubmitPermit] is dropped.
+    ///
+    /// This is cancellation safe: if the returned future is dropped before it completes, the
+    /// submission leaves the queue and does not hold a slot.
+    pub async fn acquire(&self) -> SubmitPermit<'_> {
+        let _waiting = Waiting::new(&self.waiting);
+
+        let mut last = self.last_submission.lock().await;
+        // The channel is never closed while we hold a reference to both ends.
+        self.slots.0.send(()).await.unwrap();
+        // Take ownership of the slot right away, so that it is freed if we are cancelled while
+        // pacing.
+        let permit = SubmitPermit { queue: self };
+        if let Some(last) = *last {
+            let elapsed = last.elapsed();
+            if elapsed < self.limits.min_interval {
+                sleep(self.limits.min_interval - elapsed).await;
+            }
+        }
+        *last = Some(Instant::now());
+        permit
+    }
+}
+
+// Counts a submission as waiting for as long as it is alive.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count)
+    }
+}
+
+impl<'a> Drop for Waiting<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A turn to submit, obtained from [SubmitQueue::acquire].
+pub struct SubmitPermit<'a> {
+    queue: &'a SubmitQueue,
+}
+
+impl<'a> Drop for SubmitPermit<'a> {
+    fn drop(&mut self) {
+        // Free up our slot for the next submission.
+        self.queue.slots.1.try_recv().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future::timeout, sync::Arc, task::spawn};
+
+    #[async_std::test]
+    async fn test_submit_queue_in_flight() {
+        let queue = Arc::new(SubmitQueue::new(SubmitLimits {
+            max_in_flight: 2,
+            min_interval: Duration::ZERO,
+        }));
+        let first = queue.acquire().await;
+        let _second = queue.acquire().await;
+        assert_eq!(queue.in_flight(), 2);
+
+        // A third submission waits until one of the others is done.
+        let third = spawn({
+            let queue = queue.clone();
+            async move {
+                let _permit = queue.acquire().await;
+            }
+        });
+        while queue.queue_len() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(queue.in_flight(), 2);
+        drop(first);
+        third.await;
+        assert_eq!(queue.queue_len(), 0);
+        assert_eq!(queue.in_flight(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_submit_queue_cancellation() {
+        let queue = SubmitQueue::new(SubmitLimits {
+            max_in_flight: 1,
+            min_interval: Duration::from_secs(60),
+        });
+        drop(queue.acquire().await);
+
+        // Cancel a submission while it is being paced. It must not keep its slot or its place in
+        // the queue.
+        assert!(timeout(Duration::from_millis(50), queue.acquire())
+            .await
+            .is_err());
+        assert_eq!(queue.queue_len(), 0);
+        assert_eq!(queue.in_flight(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_submit_queue_pacing() {
+        let interval = Duration::from_millis(100);
+        let queue = SubmitQueue::new(SubmitLimits {
+            max_in_flight: 4,
+            min_interval: interval,
+        });
+        let start = Instant::now();
+        for _ in 0..3 {
+            drop(queue.acquire().await);
+        }
+        assert!(start.elapsed() >= 2 * interval);
+    }
+}
@@ -4,3 +4,4 @@
 
 pub use seahorse::testing::*;
 pub mod mocks;
+pub mod network;
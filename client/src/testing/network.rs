@@ -0,0 +1,88 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Keystores connected to a local test network.
+
+use crate::{network::NetworkBackend, EspressoKeystore};
+use espresso_core::universal_params::UNIVERSAL_PARAM;
+use espresso_validator::testing::{minimal_test_network, TestNetwork, UnencryptedKeystoreLoader};
+use jf_cap::keys::UserKeyPair;
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+use tempdir::TempDir;
+
+pub use espresso_validator::testing::retry;
+
+pub type TestKeystore = EspressoKeystore<'static, NetworkBackend<'static>, ()>;
+
+/// A minimal test network with a keystore which owns the faucet record created at genesis.
+pub struct FundedNetwork {
+    /// A keystore with the faucet key as a sending account, scanned up to the genesis grant.
+    pub keystore: TestKeystore,
+    pub faucet: UserKeyPair,
+    pub network: TestNetwork,
+    // Storage for the keystores created on this network, which must outlive them.
+    loaders: Vec<UnencryptedKeystoreLoader>,
+}
+
+impl FundedNetwork {
+    pub async fn new(seed: u8) -> Self {
+        let mut rng = ChaChaRng::from_seed([seed; 32]);
+        let faucet = UserKeyPair::generate(&mut rng);
+        let network = minimal_test_network(&mut rng, faucet.pub_key(), None).await;
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("funded_network").unwrap(),
+        };
+        let mut keystore = EspressoKeystore::new(Self::connect(&network).await, &mut loader)
+            .await
+            .unwrap();
+        keystore
+            .add_account(faucet.clone(), "faucet".into(), Default::default())
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&faucet.address())
+            .await
+            .unwrap();
+        Self {
+            keystore,
+            faucet,
+            network,
+            loaders: vec![loader],
+        }
+    }
+
+    /// A new backend connected to this network.
+    pub async fn backend(&self) -> NetworkBackend<'static> {
+        Self::connect(&self.network).await
+    }
+
+    /// Create a new, empty keystore on this network.
+    pub async fn new_keystore(&mut self) -> TestKeystore {
+        let backend = self.backend().await;
+        self.new_keystore_with_backend(backend).await
+    }
+
+    /// Create a new, empty keystore using `backend`, which should be connected to this network.
+    pub async fn new_keystore_with_backend(
+        &mut self,
+        backend: NetworkBackend<'static>,
+    ) -> TestKeystore {
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("funded_network").unwrap(),
+        };
+        let keystore = EspressoKeystore::new(backend, &mut loader).await.unwrap();
+        self.loaders.push(loader);
+        keystore
+    }
+
+    async fn connect(network: &TestNetwork) -> NetworkBackend<'static> {
+        NetworkBackend::new(
+            &UNIVERSAL_PARAM,
+            network.query_api.clone(),
+            network.address_book_api.clone(),
+            network.submit_api.clone(),
+        )
+        .await
+        .unwrap()
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Tracking the outcome of submitted transactions.
+//!
+//! The keystore can report whether a transaction it submitted is still pending, succeeded, or
+//! failed, but not where it ended up on the ledger. [TransactionTracker] combines the keystore's
+//! view with queries to the EsQS, so that callers can learn which block a transaction was included
+//! in and the UIDs of its output records, and can wait for a transaction with a timeout instead of
+//! writing their own polling loop.
+//!
+//! The tracker can also check transactions which this keystore did not submit: a merchant given a
+//! receipt by a customer can use [TransactionTracker::verify_payment] to confirm that the receipt
+//! refers to a real transaction on the ledger which paid the merchant's keystore.
+
+use crate::{connection::QueryConnection, EspressoKeystore, EspressoKeystoreError};
+use async_std::{future::timeout, sync::Arc};
+use espresso_availability_api::query_data::{RecordQueryData, TransactionQueryData};
+use espresso_core::{ledger::EspressoLedger, state::TransactionCommitment};
+use jf_cap::structs::{AssetCode, RecordCommitment};
+use reef::traits::Transaction as _;
+use seahorse::{ledger_state::TransactionUID, KeystoreBackend, RecordAmount};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// The outcome of a submitted transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// The transaction has not yet been included in a block or rejected.
+    Pending,
+    /// The transaction was included in a block.
+    Accepted {
+        /// The height of the block which included the transaction.
+        block_id: u64,
+        /// The index of the transaction within its block.
+        txn_id: u64,
+        /// The UIDs of the transaction's output records, in order.
+        uids: Vec<u64>,
+    },
+    /// The transaction was rejected by the validators and will never be included in a block.
+    Rejected,
+    /// The transaction was still pending when the caller stopped waiting for it.
+    TimedOut,
+}
+
+impl TransactionOutcome {
+    /// Whether this outcome will never change.
+    pub fn is_final(&self) -> bool {
+        matches!(self, Self::Accepted { .. } | Self::Rejected)
+    }
+}
+
+/// The result of [TransactionTracker::verify_payment].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PaymentVerification {
+    /// The transaction is on the ledger and paid at least the expected amount to this keystore.
+    Verified {
+        block_id: u64,
+        txn_id: u64,
+        /// The total amount of the expected asset paid to this keystore.
+        amount: u128,
+    },
+    /// The transaction is on the ledger, but paid less than the expected amount to this keystore.
+    ///
+    /// This includes transactions which paid nothing to this keystore, and transactions whose
+    /// outputs have not yet been received by the keystore, because it has not yet processed their
+    /// block.
+    Underpaid {
+        block_id: u64,
+        txn_id: u64,
+        amount: u128,
+    },
+    /// There is no transaction with this receipt on the ledger, at least not yet.
+    NotFound,
+}
+
+/// Queries the EsQS about transactions.
+///
+/// A tracker shares its [QueryConnection] with a keystore backend (see
+/// [NetworkBackend::transaction_tracker](crate::network::NetworkBackend::transaction_tracker)), so
+/// its queries fail over between EsQS endpoints, and use the same timeouts and backoff, as the
+/// backend's own.
+pub struct TransactionTracker {
+    queries: Arc<QueryConnection>,
+}
+
+impl TransactionTracker {
+    pub fn new(queries: Arc<QueryConnection>) -> Self {
+        Self { queries }
+    }
+
+    /// Get the current outcome of `receipt` without waiting.
+    pub async fn transaction_status<'a, Backend, Meta>(
+        &self,
+        keystore: &EspressoKeystore<'a, Backend, Meta>,
+        receipt: &TransactionUID<EspressoLedger>,
+    ) -> Result<TransactionOutcome, EspressoKeystoreError>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        let status = keystore.transaction_status(receipt).await?;
+        if !status.is_final() {
+            Ok(TransactionOutcome::Pending)
+        } else if status.succeeded() {
+            self.accepted(receipt).await
+        } else {
+            Ok(TransactionOutcome::Rejected)
+        }
+    }
+
+    /// Wait until `receipt` is accepted or rejected, or until `timeout` elapses.
+    ///
+    /// If `timeout` elapses first, the result is [TransactionOutcome::TimedOut]. The transaction
+    /// may still be accepted later.
+    pub async fn await_transaction<'a, Backend, Meta>(
+        &self,
+        keystore: &EspressoKeystore<'a, Backend, Meta>,
+        receipt: &TransactionUID<EspressoLedger>,
+        timeout_after: Duration,
+    ) -> Result<TransactionOutcome, EspressoKeystoreError>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        match timeout(timeout_after, keystore.await_transaction(receipt)).await {
+            Ok(status) => {
+                if status?.succeeded() {
+                    self.accepted(receipt).await
+                } else {
+                    Ok(TransactionOutcome::Rejected)
+                }
+            }
+            Err(_) => Ok(TransactionOutcome::TimedOut),
+        }
+    }
+
+    /// Check that `receipt` refers to a transaction which paid `expected` of `asset` to `keystore`.
+    ///
+    /// The receipt may come from anyone, such as a customer claiming to have paid. The transaction
+    /// is looked up on the ledger, and its outputs are matched against the unspent records owned
+    /// by the keystore's sending accounts, so outputs which the keystore has already spent are not
+    /// counted. Make sure the keystore has caught up with the block containing the transaction
+    /// before relying on an [Underpaid](PaymentVerification::Underpaid) result.
+    pub async fn verify_payment<'a, Backend, Meta>(
+        &self,
+        keystore: &EspressoKeystore<'a, Backend, Meta>,
+        receipt: &TransactionUID<EspressoLedger>,
+        asset: &AssetCode,
+        expected: RecordAmount,
+    ) -> Result<PaymentVerification, EspressoKeystoreError>
+    where
+        Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+        Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+    {
+        let hash = TransactionCommitment(receipt.0);
+        // The availability API reports an unknown transaction hash as a bad request, which
+        // `get_optional` treats as not found.
+        let txn: TransactionQueryData = match self
+            .queries
+            .get_optional(&format!("availability/gettransaction/hash/{}", hash))
+            .await?
+        {
+            Some(txn) => txn,
+            None => return Ok(PaymentVerification::NotFound),
+        };
+
+        let outputs: HashSet<RecordCommitment> = txn
+            .raw_transaction
+            .output_commitments()
+            .into_iter()
+            .collect();
+        let owners: HashSet<_> = keystore
+            .sending_keys()
+            .await
+            .into_iter()
+            .map(|key| key.pub_key())
+            .collect();
+        let amount = keystore
+            .records()
+            .await
+            .into_iter()
+            .filter(|record| {
+                let ro = &record.record_opening();
+                ro.asset_def.code == *asset
+                    && owners.contains(&ro.pub_key)
+                    && outputs.contains(&RecordCommitment::from(ro))
+            })
+            .fold(0u128, |total, record| {
+                total.saturating_add(record.amount().as_u128())
+            });
+
+        Ok(if amount >= expected.as_u128() {
+            PaymentVerification::Verified {
+                block_id: txn.block_id,
+                txn_id: txn.txn_id,
+                amount,
+            }
+        } else {
+            PaymentVerification::Underpaid {
+                block_id: txn.block_id,
+                txn_id: txn.txn_id,
+                amount,
+            }
+        })
+    }
+
+    /// Look up the location of an accepted transaction.
+    async fn accepted(
+        &self,
+        receipt: &TransactionUID<EspressoLedger>,
+    ) -> Result<TransactionOutcome, EspressoKeystoreError> {
+        let hash = TransactionCommitment(receipt.0);
+        let txn: TransactionQueryData = self
+            .queries
+            .get(&format!("availability/gettransaction/hash/{}", hash))
+            .await?;
+        let mut uids = Vec::with_capacity(txn.raw_transaction.output_len());
+        for output in 0..txn.raw_transaction.output_len() {
+            let record: RecordQueryData = self
+                .queries
+                .get(&format!(
+                    "availability/getrecord/{}/{}/{}",
+                    txn.block_id, txn.txn_id, output
+                ))
+                .await?;
+            uids.push(record.uid);
+        }
+        Ok(TransactionOutcome::Accepted {
+            block_id: txn.block_id,
+            txn_id: txn.txn_id,
+            uids,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::{retry, FundedNetwork};
+    use ark_serialize::CanonicalDeserialize;
+    use primitive_types::U256;
+
+    #[async_std::test]
+    async fn test_track_payment() {
+        let mut network = FundedNetwork::new(5).await;
+        let mut merchant = network.new_keystore().await;
+        let merchant_key = merchant
+            .generate_sending_account("merchant".into(), None)
+            .await
+            .unwrap();
+        let tracker = network.backend().await.transaction_tracker();
+
+        let receipt = network
+            .keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(merchant_key.clone(), RecordAmount::from(100u64))],
+                RecordAmount::from(1u64),
+            )
+            .await
+            .unwrap();
+        let outcome = tracker
+            .await_transaction(&network.keystore, &receipt, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let (block_id, txn_id) = match &outcome {
+            TransactionOutcome::Accepted {
+                block_id,
+                txn_id,
+                uids,
+            } => {
+                // One output for the merchant, and one for the change.
+                assert_eq!(uids.len(), 2);
+                (*block_id, *txn_id)
+            }
+            _ => panic!("payment was not accepted: {:?}", outcome),
+        };
+        assert_eq!(
+            tracker
+                .transaction_status(&network.keystore, &receipt)
+                .await
+                .unwrap(),
+            outcome
+        );
+
+        // The merchant can verify the payment once it has received it.
+        retry(|| async {
+            merchant
+                .balance_breakdown(&merchant_key.address(), &AssetCode::native())
+                .await
+                == U256::from(100u64)
+        })
+        .await;
+        assert_eq!(
+            tracker
+                .verify_payment(
+                    &merchant,
+                    &receipt,
+                    &AssetCode::native(),
+                    RecordAmount::from(100u64)
+                )
+                .await
+                .unwrap(),
+            PaymentVerification::Verified {
+                block_id,
+                txn_id,
+                amount: 100
+            }
+        );
+        assert_eq!(
+            tracker
+                .verify_payment(
+                    &merchant,
+                    &receipt,
+                    &AssetCode::native(),
+                    RecordAmount::from(101u64)
+                )
+                .await
+                .unwrap(),
+            PaymentVerification::Underpaid {
+                block_id,
+                txn_id,
+                amount: 100
+            }
+        );
+
+        // A made-up receipt is not on the ledger.
+        let fake = TransactionUID(
+            TransactionCommitment::deserialize(&[1u8; 32][..])
+                .unwrap()
+                .0,
+        );
+        assert_eq!(
+            tracker
+                .verify_payment(
+                    &merchant,
+                    &fake,
+                    &AssetCode::native(),
+                    RecordAmount::from(100u64)
+                )
+                .await
+                .unwrap(),
+            PaymentVerification::NotFound
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Transfers which report whether a failure happened before or after submission.
+//!
+//! [Keystore::transfer](seahorse::Keystore::transfer) builds a transaction and submits it in one
+//! step, so when it fails the caller cannot tell whether the transaction ever left the keystore.
+//! Features which must never pay twice need to know: a transfer which could not be built can safely
+//! be retried, but one whose submission failed may still have reached a validator.
+
+use crate::{EspressoKeystore, EspressoKeystoreError};
+use espresso_core::ledger::EspressoLedger;
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::AssetCode,
+    TransactionNote,
+};
+use seahorse::{
+    ledger_state::TransactionUID, txn_builder::TransactionError, KeystoreBackend, KeystoreError,
+    RecordAmount,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// A failed [transfer].
+#[derive(Debug)]
+pub(crate) enum TransferFailure {
+    /// The transfer could not be built, so nothing was submitted.
+    NotSubmitted(EspressoKeystoreError),
+    /// Submitting the transfer failed, but it may still have reached a validator and may still be
+    /// included in a block.
+    MaybeSubmitted(EspressoKeystoreError),
+}
+
+impl TransferFailure {
+    /// Whether the transfer could not be built because the sender does not have enough spendable
+    /// records.
+    ///
+    /// This includes records which are on hold as inputs to pending transactions, so it may resolve
+    /// itself once those transactions complete.
+    pub(crate) fn is_insufficient_balance(&self) -> bool {
+        matches!(
+            self,
+            Self::NotSubmitted(KeystoreError::TransactionError {
+                source: TransactionError::InsufficientBalance { .. },
+            })
+        )
+    }
+
+    pub(crate) fn into_error(self) -> EspressoKeystoreError {
+        match self {
+            Self::NotSubmitted(err) | Self::MaybeSubmitted(err) => err,
+        }
+    }
+}
+
+impl Display for TransferFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::NotSubmitted(err) => write!(f, "{}", err),
+            Self::MaybeSubmitted(err) => write!(f, "submission failed: {}", err),
+        }
+    }
+}
+
+/// Transfer `asset` from `account` to `receivers`, like
+/// [Keystore::transfer](seahorse::Keystore::transfer).
+pub(crate) async fn transfer<'a, Backend, Meta>(
+    keystore: &mut EspressoKeystore<'a, Backend, Meta>,
+    account: Option<&UserAddress>,
+    asset: &AssetCode,
+    receivers: &[(UserPubKey, RecordAmount)],
+    fee: RecordAmount,
+) -> Result<TransactionUID<EspressoLedger>, TransferFailure>
+where
+    Backend: 'a + KeystoreBackend<'a, EspressoLedger> + Send + Sync,
+    Meta: 'a + Serialize + DeserializeOwned + Send + Sync + Clone + PartialEq,
+{
+    let receivers = receivers
+        .iter()
+        .map(|(key, amount)| (key.clone(), *amount, false))
+        .collect::<Vec<_>>();
+    let (note, params) = keystore
+        .build_transfer(account, asset, &receivers, fee, vec![], None)
+        .await
+        .map_err(TransferFailure::NotSubmitted)?;
+    keystore
+        .submit_cap(TransactionNote::Transfer(Box::new(note)), params)
+        .await
+        .map_err(TransferFailure::MaybeSubmitted)
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::testing::network::FundedNetwork;
+    use seahorse::ledger_state::TransactionStatus;
+
+    #[async_std::test]
+    async fn test_transfer() {
+        let mut network = FundedNetwork::new(1).await;
+        let mut receiver = network.new_keystore().await;
+        let receiver_key = receiver
+            .generate_sending_account("receiver".into(), None)
+            .await
+            .unwrap();
+        let faucet = network.faucet.address();
+
+        let receipt = transfer(
+            &mut network.keystore,
+            Some(&faucet),
+            &AssetCode::native(),
+            &[(receiver_key.clone(), RecordAmount::from(100u64))],
+            RecordAmount::from(1u64),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            network.keystore.await_transaction(&receipt).await.unwrap(),
+            TransactionStatus::Retired
+        ));
+
+        // More than the faucet owns: the transfer is never built, let alone submitted.
+        let failure = transfer(
+            &mut network.keystore,
+            Some(&faucet),
+            &AssetCode::native(),
+            &[(receiver_key, RecordAmount::from(1u64 << 33))],
+            RecordAmount::from(1u64),
+        )
+        .await
+        .unwrap_err();
+        assert!(failure.is_insufficient_balance(), "{}", failure);
+    }
+}
@@ -12,6 +12,7 @@ pub mod set_merkle_tree;
 pub mod stake_table;
 pub mod state;
 pub mod testing;
+pub mod transfer_plan;
 pub mod tree_hash;
 pub mod universal_params;
 
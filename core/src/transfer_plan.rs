@@ -0,0 +1,310 @@
+#![deny(warnings)]
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Selection of transfer circuit sizes.
+//!
+//! A CAP transfer note is proven using a circuit with a fixed number of inputs and outputs, and the
+//! ledger only supports the sizes in [SUPPORTED_TRANSFER_SIZES]. Users should not have to know
+//! which sizes are valid, so this module chooses the cheapest supported size for a transfer, and
+//! when no single supported size fits, splits the transfer into a chain of smaller transfers.
+//!
+//! Every transfer note spends one fee input and creates one fee change output. When the transferred
+//! asset is the native asset, the fee input is one of the records being transferred and the fee
+//! change output also holds the change, so a note spends only records of the asset and has one
+//! output in addition to its receivers. For any other asset, the fee input is an extra native
+//! record, and the note creates a separate change output for the transferred asset, so it has one
+//! input and two outputs in addition to its asset inputs and receivers. Notes which use fewer
+//! inputs or outputs than their circuit size are padded with dummy records.
+
+use crate::universal_params::SUPPORTED_TRANSFER_SIZES;
+
+/// One transfer note in a [plan_transfer] plan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlannedTransfer {
+    /// The `(inputs, outputs)` size of the circuit to use for this note.
+    pub size: (usize, usize),
+    /// Whether the transferred asset is the native asset.
+    pub native: bool,
+    /// The number of records of the transferred asset spent by this note.
+    ///
+    /// For a non-native asset, this does not include the fee input.
+    pub asset_inputs: usize,
+    /// The number of receivers paid by this note.
+    ///
+    /// A note with no receivers merges its asset inputs into a single change record.
+    pub receivers: usize,
+}
+
+impl PlannedTransfer {
+    /// The number of inputs this note uses, including the fee input.
+    pub fn num_inputs(&self) -> usize {
+        self.asset_inputs + extra_inputs(self.native)
+    }
+
+    /// The number of outputs this note uses, including the change outputs.
+    pub fn num_outputs(&self) -> usize {
+        self.receivers + extra_outputs(self.native)
+    }
+}
+
+/// The number of inputs a note uses in addition to the records of the transferred asset.
+fn extra_inputs(native: bool) -> usize {
+    if native {
+        0
+    } else {
+        1
+    }
+}
+
+/// The number of outputs a note uses in addition to its receivers.
+fn extra_outputs(native: bool) -> usize {
+    if native {
+        1
+    } else {
+        2
+    }
+}
+
+/// The cheapest supported size with at least `inputs` inputs and `outputs` outputs.
+///
+/// Circuits are compared by their total number of inputs and outputs, with ties broken in favor of
+/// fewer inputs.
+pub fn transfer_size(inputs: usize, outputs: usize) -> Option<(usize, usize)> {
+    transfer_size_from(&SUPPORTED_TRANSFER_SIZES, inputs, outputs)
+}
+
+/// Plan a transfer spending `asset_inputs` records to pay `receivers` receivers.
+///
+/// `native` says whether the records are of the native asset, which determines how many inputs and
+/// outputs each note needs in addition to the records and receivers.
+///
+/// The result is a sequence of notes which must be executed in order, since each note after the
+/// first spends the asset change output of the note before it. Notes which merge records come
+/// first, followed by notes which pay receivers. If there is only one note in the plan, it uses the
+/// cheapest supported size that fits the whole transfer.
+///
+/// Returns [None] if the supported sizes cannot express the transfer at all, for example if no
+/// supported circuit has enough outputs to pay even a single receiver, or if there are receivers
+/// but no records to pay them from.
+pub fn plan_transfer(
+    native: bool,
+    asset_inputs: usize,
+    receivers: usize,
+) -> Option<Vec<PlannedTransfer>> {
+    plan_transfer_from(&SUPPORTED_TRANSFER_SIZES, native, asset_inputs, receivers)
+}
+
+/// The largest number of records of an asset which a single note can spend while paying
+/// `receivers` receivers.
+///
+/// Returns [None] if no single supported size can pay `receivers` receivers from at least one
+/// record.
+pub fn max_asset_inputs(native: bool, receivers: usize) -> Option<usize> {
+    max_asset_inputs_from(&SUPPORTED_TRANSFER_SIZES, native, receivers)
+}
+
+/// Like [transfer_size], but choosing from `sizes` instead of the supported transfer sizes.
+pub fn transfer_size_from(
+    sizes: &[(usize, usize)],
+    inputs: usize,
+    outputs: usize,
+) -> Option<(usize, usize)> {
+    sizes
+        .iter()
+        .filter(|(i, o)| *i >= inputs && *o >= outputs)
+        .min_by_key(|(i, o)| (i + o, *i))
+        .copied()
+}
+
+/// Like [max_asset_inputs], but choosing from `sizes` instead of the supported transfer sizes.
+pub fn max_asset_inputs_from(
+    sizes: &[(usize, usize)],
+    native: bool,
+    receivers: usize,
+) -> Option<usize> {
+    sizes
+        .iter()
+        .filter(|(_, o)| *o >= receivers + extra_outputs(native))
+        .map(|(i, _)| i.saturating_sub(extra_inputs(native)))
+        .filter(|inputs| *inputs > 0)
+        .max()
+}
+
+/// Like [plan_transfer], but choosing from `sizes` instead of the supported transfer sizes.
+pub fn plan_transfer_from(
+    sizes: &[(usize, usize)],
+    native: bool,
+    mut asset_inputs: usize,
+    mut receivers: usize,
+) -> Option<Vec<PlannedTransfer>> {
+    if asset_inputs == 0 && receivers > 0 {
+        return None;
+    }
+    let (extra_inputs, extra_outputs) = (extra_inputs(native), extra_outputs(native));
+    let mut plan = Vec::new();
+
+    // Merge records until all of the remaining records fit in a single note. Each merge spends as
+    // many records as possible and creates one.
+    let max_inputs = sizes.iter().map(|(i, _)| *i).max()?;
+    while asset_inputs + extra_inputs > max_inputs {
+        let merged = max_inputs - extra_inputs;
+        if merged < 2 {
+            // Merging fewer than 2 records into 1 makes no progress.
+            return None;
+        }
+        let size = transfer_size_from(sizes, max_inputs, extra_outputs)?;
+        plan.push(PlannedTransfer {
+            size,
+            native,
+            asset_inputs: merged,
+            receivers: 0,
+        });
+        asset_inputs -= merged - 1;
+    }
+
+    // Pay receivers, as many per note as the largest circuit with enough inputs allows, until the
+    // remaining receivers fit in a single note.
+    while receivers > 0 {
+        let inputs = asset_inputs + extra_inputs;
+        if let Some(size) = transfer_size_from(sizes, inputs, receivers + extra_outputs) {
+            plan.push(PlannedTransfer {
+                size,
+                native,
+                asset_inputs,
+                receivers,
+            });
+            return Some(plan);
+        }
+
+        let size = sizes
+            .iter()
+            .filter(|(i, _)| *i >= inputs)
+            .max_by_key(|(i, o)| (*o, std::cmp::Reverse(*i)))
+            .copied()?;
+        let paid = size.1.checked_sub(extra_outputs).filter(|paid| *paid > 0)?;
+        plan.push(PlannedTransfer {
+            size,
+            native,
+            asset_inputs,
+            receivers: paid,
+        });
+        receivers -= paid;
+        // The next note spends the change from this one.
+        asset_inputs = 1;
+    }
+
+    Some(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    #[test]
+    fn test_single_transfer() {
+        assert_eq!(transfer_size(2, 2), Some((2, 2)));
+        assert_eq!(transfer_size(1, 2), Some((1, 2)));
+        assert_eq!(transfer_size(2, 3), Some((3, 3)));
+        assert_eq!(transfer_size(4, 2), None);
+
+        // A non-native payment needs a fee input and two change outputs.
+        assert_eq!(
+            plan_transfer(false, 1, 1),
+            Some(vec![PlannedTransfer {
+                size: (3, 3),
+                native: false,
+                asset_inputs: 1,
+                receivers: 1,
+            }])
+        );
+        // A native payment pays its fee from the records it spends and has one change output.
+        assert_eq!(
+            plan_transfer(true, 1, 1),
+            Some(vec![PlannedTransfer {
+                size: (1, 2),
+                native: true,
+                asset_inputs: 1,
+                receivers: 1,
+            }])
+        );
+
+        // Receivers cannot be paid from no records.
+        assert_eq!(plan_transfer(true, 0, 1), None);
+        assert_eq!(plan_transfer(false, 0, 1), None);
+        assert_eq!(plan_transfer(false, 0, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn test_chained_transfers() {
+        // 4 records is too many for one note, so 2 of them are merged first, twice.
+        let plan = plan_transfer(false, 4, 1).unwrap();
+        assert_eq!(plan.len(), 3);
+        assert!(plan[..2].iter().all(|note| note.receivers == 0));
+        assert_eq!(plan[2].receivers, 1);
+
+        // Native records need no separate fee input, so one merge of 3 records is enough.
+        let plan = plan_transfer(true, 4, 1).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].asset_inputs, 3);
+        assert_eq!(plan[1].size, (2, 2));
+
+        // With outputs for only 1 non-native receiver per note, 3 receivers need 3 notes.
+        let plan = plan_transfer(false, 1, 3).unwrap();
+        assert_eq!(plan.len(), 3);
+        assert!(plan.iter().all(|note| note.receivers == 1));
+
+        // A native note can pay 2 receivers.
+        let plan = plan_transfer(true, 1, 3).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].receivers, 2);
+
+        // No supported size can pay a non-native receiver if every circuit has only 2 outputs.
+        assert_eq!(plan_transfer_from(&[(1, 2), (2, 2)], false, 1, 1), None);
+    }
+
+    #[test]
+    fn test_max_asset_inputs() {
+        assert_eq!(max_asset_inputs(true, 0), Some(3));
+        assert_eq!(max_asset_inputs(true, 1), Some(3));
+        assert_eq!(max_asset_inputs(true, 2), Some(3));
+        assert_eq!(max_asset_inputs(false, 1), Some(2));
+        assert_eq!(max_asset_inputs(false, 2), None);
+        assert_eq!(max_asset_inputs_from(&[(1, 2), (2, 2)], false, 0), Some(1));
+
+        // A single note spending that many records fits.
+        for native in [true, false] {
+            let inputs = max_asset_inputs(native, 1).unwrap();
+            assert_eq!(plan_transfer(native, inputs, 1).unwrap().len(), 1);
+            assert_eq!(plan_transfer(native, inputs + 1, 1).unwrap().len(), 2);
+        }
+    }
+
+    #[quickcheck]
+    fn quickcheck_plan_fits(native: bool, asset_inputs: u8, receivers: u8) {
+        let (asset_inputs, receivers) = (asset_inputs as usize, receivers as usize);
+        let sizes = [(1, 2), (2, 3), (3, 3), (4, 6)];
+        let plan = plan_transfer_from(&sizes, native, asset_inputs, receivers);
+        if asset_inputs == 0 && receivers > 0 {
+            assert_eq!(plan, None);
+            return;
+        }
+        let plan = plan.unwrap();
+
+        let mut records = asset_inputs;
+        for note in &plan {
+            assert!(sizes.contains(&note.size));
+            assert_eq!(note.native, native);
+            assert!(note.asset_inputs > 0);
+            assert!(note.num_inputs() <= note.size.0);
+            assert!(note.num_outputs() <= note.size.1);
+            assert!(note.asset_inputs <= records);
+            records = records - note.asset_inputs + 1;
+        }
+        assert_eq!(
+            plan.iter().map(|note| note.receivers).sum::<usize>(),
+            receivers
+        );
+    }
+}